@@ -0,0 +1,307 @@
+use std::{
+    cmp::Ordering,
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    mem,
+};
+
+use crate::{
+    heap::{GreyStack, Heap, Trace},
+    opcodes::{Int, Number, Value},
+};
+
+/// The largest power of ten that fits in a limb, used to print a limb's worth of digits at a time.
+const DECIMAL_BASE: u32 = 1_000_000_000;
+const DECIMAL_BASE_DIGITS: usize = 9;
+
+/// An arbitrary precision integer. Ints are promoted to these when arithmetic on them
+/// overflows and results are turned back into ints whenever they fit.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    /// The magnitude's base 2^32 digits, least significant first. There are never any
+    /// trailing zero limbs, so zero is empty and every value has a single representation.
+    limbs: Vec<u32>,
+}
+
+impl BigInt {
+    fn from_parts(negative: bool, mut limbs: Vec<u32>) -> Self {
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+
+        Self {
+            negative: negative && !limbs.is_empty(),
+            limbs,
+        }
+    }
+
+    pub fn from_int(value: Int) -> Self {
+        let magnitude = value.unsigned_abs();
+        Self::from_parts(value < 0, vec![magnitude as u32, (magnitude >> 32) as u32])
+    }
+
+    /// Converts the integer part of `value`, or returns None for infinities and NaN.
+    pub fn from_float(value: Number) -> Option<Self> {
+        if !value.is_finite() {
+            return None;
+        }
+
+        let value = value.trunc();
+        if value.abs() < 1.0 {
+            return Some(Self::from_parts(false, Vec::new()));
+        }
+
+        // A float is its 53 bit mantissa shifted by its exponent.
+        let bits = value.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i64 - 1075;
+        let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+
+        let limbs = if exponent < 0 {
+            let mantissa = mantissa >> -exponent;
+            vec![mantissa as u32, (mantissa >> 32) as u32]
+        } else {
+            let mut limbs = vec![0; exponent as usize / 32];
+            let wide = (mantissa as u128) << (exponent % 32);
+            limbs.extend(&[wide as u32, (wide >> 32) as u32, (wide >> 64) as u32]);
+            limbs
+        };
+
+        Some(Self::from_parts(value < 0.0, limbs))
+    }
+
+    /// Parses an optionally signed run of digits in `radix`, without any prefix.
+    pub fn parse(text: &str, radix: u32) -> Option<Self> {
+        let (negative, digits) = match text.as_bytes().first() {
+            Some(b'-') => (true, &text[1..]),
+            Some(b'+') => (false, &text[1..]),
+            _ => (false, text),
+        };
+
+        if digits.is_empty() {
+            return None;
+        }
+
+        let mut limbs = Vec::new();
+        for ch in digits.chars() {
+            let digit = ch.to_digit(radix)?;
+            mul_small(&mut limbs, radix);
+            add_small(&mut limbs, digit);
+        }
+
+        Some(Self::from_parts(negative, limbs))
+    }
+
+    /// Makes a value out of this, which is an int whenever it's in range.
+    pub fn into_value(self, heap: &Heap) -> Value {
+        match self.to_int() {
+            Some(int) => Value::Int(int),
+            None => Value::BigInt(heap.manage(self)),
+        }
+    }
+
+    /// Returns the value as an int if it's in range.
+    pub fn to_int(&self) -> Option<Int> {
+        if self.limbs.len() > 2 {
+            return None;
+        }
+
+        let magnitude = self
+            .limbs
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &limb| (acc << 32) | limb as u64);
+
+        if self.negative {
+            0i64.checked_sub_unsigned(magnitude)
+        } else {
+            Int::try_from(magnitude).ok()
+        }
+    }
+
+    pub fn to_float(&self) -> Number {
+        let magnitude = self
+            .limbs
+            .iter()
+            .rev()
+            .fold(0.0, |acc, &limb| acc * 4294967296.0 + limb as Number);
+
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    pub fn neg(&self) -> Self {
+        Self::from_parts(!self.negative, self.limbs.clone())
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        if self.negative == other.negative {
+            return Self::from_parts(self.negative, add_magnitudes(&self.limbs, &other.limbs));
+        }
+
+        // Opposite signs subtract the smaller magnitude from the larger, which decides the sign.
+        match compare_magnitudes(&self.limbs, &other.limbs) {
+            Ordering::Less => Self::from_parts(other.negative, sub_magnitudes(&other.limbs, &self.limbs)),
+            _ => Self::from_parts(self.negative, sub_magnitudes(&self.limbs, &other.limbs)),
+        }
+    }
+
+    pub fn sub(&self, other: &Self) -> Self {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        let mut limbs = vec![0u32; self.limbs.len() + other.limbs.len()];
+
+        for (i, &lhs) in self.limbs.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &rhs) in other.limbs.iter().enumerate() {
+                let product = limbs[i + j] as u64 + lhs as u64 * rhs as u64 + carry;
+                limbs[i + j] = product as u32;
+                carry = product >> 32;
+            }
+            limbs[i + other.limbs.len()] = carry as u32;
+        }
+
+        Self::from_parts(self.negative != other.negative, limbs)
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitudes(&self.limbs, &other.limbs),
+            (true, true) => compare_magnitudes(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for BigInt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.limbs.is_empty() {
+            return write!(f, "0");
+        }
+
+        // Peel off nine decimal digits at a time, least significant first.
+        let mut limbs = self.limbs.clone();
+        let mut chunks = Vec::new();
+        while !limbs.is_empty() {
+            chunks.push(div_small(&mut limbs, DECIMAL_BASE));
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+
+        let mut chunks = chunks.iter().rev();
+        write!(f, "{}", chunks.next().unwrap())?;
+        for chunk in chunks {
+            write!(f, "{:0width$}", chunk, width = DECIMAL_BASE_DIGITS)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Trace for BigInt {
+    fn trace(&self, _grey_stack: &mut GreyStack) {}
+
+    fn bytes_allocated(&self) -> usize {
+        mem::size_of::<Self>() + self.limbs.capacity() * mem::size_of::<u32>()
+    }
+}
+
+fn compare_magnitudes(lhs: &[u32], rhs: &[u32]) -> Ordering {
+    lhs.len()
+        .cmp(&rhs.len())
+        .then_with(|| lhs.iter().rev().cmp(rhs.iter().rev()))
+}
+
+fn add_magnitudes(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let (longer, shorter) = if lhs.len() >= rhs.len() { (lhs, rhs) } else { (rhs, lhs) };
+
+    let mut limbs = Vec::with_capacity(longer.len() + 1);
+    let mut carry = 0u64;
+    for (i, &limb) in longer.iter().enumerate() {
+        let sum = limb as u64 + *shorter.get(i).unwrap_or(&0) as u64 + carry;
+        limbs.push(sum as u32);
+        carry = sum >> 32;
+    }
+    limbs.push(carry as u32);
+
+    limbs
+}
+
+/// Subtracts `rhs` from `lhs`, whose magnitude must be at least as large.
+fn sub_magnitudes(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let mut limbs = Vec::with_capacity(lhs.len());
+    let mut borrow = 0i64;
+    for (i, &limb) in lhs.iter().enumerate() {
+        let mut difference = limb as i64 - *rhs.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if difference < 0 {
+            difference += 1 << 32;
+            borrow = 1;
+        }
+        limbs.push(difference as u32);
+    }
+
+    limbs
+}
+
+fn mul_small(limbs: &mut Vec<u32>, factor: u32) {
+    let mut carry = 0u64;
+    for limb in limbs.iter_mut() {
+        let product = *limb as u64 * factor as u64 + carry;
+        *limb = product as u32;
+        carry = product >> 32;
+    }
+
+    if carry > 0 {
+        limbs.push(carry as u32);
+    }
+}
+
+fn add_small(limbs: &mut Vec<u32>, addend: u32) {
+    let mut carry = addend as u64;
+    for limb in limbs.iter_mut() {
+        if carry == 0 {
+            return;
+        }
+
+        let sum = *limb as u64 + carry;
+        *limb = sum as u32;
+        carry = sum >> 32;
+    }
+
+    if carry > 0 {
+        limbs.push(carry as u32);
+    }
+}
+
+/// Divides in place, dropping any leading zero limbs, and returns the remainder.
+fn div_small(limbs: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut remainder = 0u64;
+    for limb in limbs.iter_mut().rev() {
+        let dividend = (remainder << 32) | *limb as u64;
+        *limb = (dividend / divisor as u64) as u32;
+        remainder = dividend % divisor as u64;
+    }
+
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+
+    remainder as u32
+}
@@ -1,10 +1,12 @@
-use std::{convert::{TryInto, identity}, todo};
+use std::{collections::HashSet, convert::{TryInto, identity}, rc::Rc, todo};
 
 use crate::{
+    bigint::BigInt,
     heap::{Gc, Heap, LoxStr},
     object::{FunctionType, LoxFun, UpvalueSim},
     opcodes::{ArgCount, ByteCodeOffset, ChunkIterator, ConstantIndex, Number},
     precedence::{parse_rule, ParseRule, Precedence},
+    types::{ClassInfo, Diagnostic, Signature, Type, TypeChecker, VarType},
     vm::StackIndex,
 };
 
@@ -37,15 +39,33 @@ pub struct Compiler<'a> {
     ctx_stk: Vec<CompilerContext<'a>>,
     curr_ctx: usize,
     class_ctxs: Vec<ClassContext<'a>>,
-    pub heap: Heap,
+    /// Globals declared with 'const' so far, used to reject assignments at compile time.
+    const_globals: HashSet<&'a str>,
+    /// Works out the types of what gets compiled when checking a script's type annotations.
+    types: Option<TypeChecker<'a>>,
+    heap: &'a Heap,
 }
 
 impl<'a> Compiler<'a> {
-    pub fn new(src: &'a str) -> Self {
+    pub fn new(src: &'a str, heap: &'a Heap) -> Self {
+        Self::with_function_type(src, heap, FunctionType::Script, None)
+    }
+
+    /// Compiles an imported file. Its top level code returns the module object
+    /// that the vm places in its first stack slot. Errors name the module's `path`.
+    pub fn new_module(src: &'a str, heap: &'a Heap, path: Gc<LoxStr>) -> Self {
+        Self::with_function_type(src, heap, FunctionType::Module, Some(path))
+    }
+
+    fn with_function_type(
+        src: &'a str,
+        heap: &'a Heap,
+        function_type: FunctionType,
+        module: Option<Gc<LoxStr>>,
+    ) -> Self {
         let scanner = Scanner::new(src);
-        let heap = Heap::new();
         let empty_string = heap.intern_string("");
-        let ctx = CompilerContext::new(FunctionType::Script, empty_string);
+        let ctx = CompilerContext::new(function_type, empty_string, module);
 
         Compiler {
             scanner,
@@ -53,10 +73,23 @@ impl<'a> Compiler<'a> {
             ctx_stk: vec![ctx],
             curr_ctx: 0,
             class_ctxs: Vec::new(),
+            const_globals: HashSet::new(),
+            types: None,
             heap,
         }
     }
 
+    /// Checks the script's types while compiling it, as `lox check --types` does.
+    pub fn with_type_checking(mut self) -> Self {
+        self.types = Some(TypeChecker::new());
+        self
+    }
+
+    /// The type errors found while compiling, in line order.
+    pub fn type_diagnostics(&mut self) -> Vec<Diagnostic> {
+        self.types.as_mut().map_or_else(Vec::new, TypeChecker::finish)
+    }
+
     pub fn compile(&mut self) -> Option<Gc<LoxFun>> {
         self.advance();
 
@@ -119,6 +152,11 @@ impl<'a> Compiler<'a> {
         self.tin.cur.kind == token_type
     }
 
+    /// Looks at the kind of the token after the current one without consuming anything.
+    fn peek_next(&self) -> TokenType {
+        self.scanner.clone().scan_token().kind
+    }
+
     fn match_tt(&mut self, token_type: TokenType) -> bool {
         if self.check(token_type) {
             self.advance();
@@ -179,7 +217,8 @@ impl<'a> Compiler<'a> {
     }
 
     fn emit_return(&mut self) {
-        if cctx!(self).function_type == FunctionType::Initializer {
+        let function_type = &cctx!(self).function_type;
+        if *function_type == FunctionType::Initializer || *function_type == FunctionType::Module {
             self.emit_instruction(Instruction::GetLocal(0));
         } else {
             self.emit_instruction(Instruction::Nil);
@@ -218,13 +257,22 @@ impl<'a> Compiler<'a> {
 
             match self.tin.cur.kind {
                 TokenType::Class
+                | TokenType::Enum
+                | TokenType::Trait
                 | TokenType::Fun
                 | TokenType::Var
+                | TokenType::Const
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => {
+                | TokenType::Return
+                | TokenType::Match
+                | TokenType::Throw
+                | TokenType::Assert
+                | TokenType::Try
+                | TokenType::Import
+                | TokenType::From => {
                     return;
                 }
                 _ => self.advance(),
@@ -233,8 +281,30 @@ impl<'a> Compiler<'a> {
     }
 
     pub fn number(&mut self) {
-        let value: Number = self.tin.pre.description.parse().unwrap();
-        self.emit_constant(Value::Number(value))
+        // The scanner has already checked the literal's shape.
+        // Literals with a fraction or exponent are floats and the rest are ints, or BigInts
+        // when they're too large for an int.
+        let literal = self.tin.pre.description.replace('_', "");
+        let value = match literal.get(..2) {
+            Some("0x") | Some("0X") => BigInt::parse(&literal[2..], 16).map(|value| value.into_value(self.heap)),
+            Some("0b") | Some("0B") => BigInt::parse(&literal[2..], 2).map(|value| value.into_value(self.heap)),
+            _ if literal.contains(|c| c == '.' || c == 'e' || c == 'E') => {
+                literal.parse::<Number>().ok().filter(|value| value.is_finite()).map(Value::Number)
+            }
+            _ => BigInt::parse(&literal, 10).map(|value| value.into_value(self.heap)),
+        };
+
+        match value {
+            Some(Value::Number(value)) => {
+                self.emit_constant(Value::Number(value));
+                self.set_type(Type::Float);
+            }
+            Some(value) => {
+                self.emit_constant(value);
+                self.set_type(Type::Int);
+            }
+            None => self.error_at_previous("Number literal is too large."),
+        }
     }
 
     pub fn literal(&mut self) {
@@ -244,18 +314,34 @@ impl<'a> Compiler<'a> {
             TokenType::True => self.emit_instruction(Instruction::True),
             _ => panic!("Non literal token found in literal() parse"),
         }
+
+        let ty = if self.tin.pre.kind == TokenType::Nil { Type::Nil } else { Type::Bool };
+        self.set_type(ty);
     }
 
     pub fn call(&mut self) {
-        let arg_Count = self.argument_count();
+        let callee = self.expression_type();
+        let (arg_Count, arg_types) = self.arguments();
         self.emit_instruction(Instruction::Call(arg_Count));
+
+        let line = self.tin.pre.line;
+        self.check_type(|types| types.call(line, &callee, &arg_types));
     }
 
     fn argument_count(&mut self) -> ArgCount {
+        self.arguments().0
+    }
+
+    /// Compiles a call's arguments, returning how many there are along with their types.
+    fn arguments(&mut self) -> (ArgCount, Vec<Type<'a>>) {
         let mut arg_count: ArgCount = 0;
+        let mut arg_types = Vec::new();
         if !self.check(TokenType::RightParen) {
             loop {
                 self.expression();
+                if self.types.is_some() {
+                    arg_types.push(self.expression_type());
+                }
 
                 if arg_count == ArgCount::MAX {
                     cctx!(self)
@@ -271,31 +357,99 @@ impl<'a> Compiler<'a> {
         }
         self.consume(TokenType::RightParen, "Expect ')' after arguments.");
 
-        arg_count
+        (arg_count, arg_types)
     }
 
     pub fn grouping(&mut self) {
+        if self.is_arrow_function() {
+            self.arrow_function();
+            return;
+        }
+
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        self.set_type(self.expression_type());
+    }
+
+    /// Checks whether the '(' just consumed starts an arrow function's parameter list by
+    /// scanning ahead to its matching ')' and looking for a '=>' after it.
+    fn is_arrow_function(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut token = self.tin.cur;
+        let mut depth = 1;
+
+        loop {
+            match token.kind {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return scanner.scan_token().kind == TokenType::Arrow;
+                    }
+                }
+                TokenType::EOF => return false,
+                _ => {}
+            }
+
+            token = scanner.scan_token();
+        }
+    }
+
+    pub fn lambda(&mut self) {
+        self.begin_function(FunctionType::Function, ANONYMOUS_FUNCTION_NAME);
+        self.consume(TokenType::LeftParen, "Expect '(' after 'fun'.");
+        self.parameters();
+        self.return_type_annotation();
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+        self.end_function();
+    }
+
+    fn arrow_function(&mut self) {
+        self.begin_function(FunctionType::Function, ANONYMOUS_FUNCTION_NAME);
+        self.parameters();
+        self.consume(TokenType::Arrow, "Expect '=>' after parameters.");
+
+        if self.match_tt(TokenType::LeftBrace) {
+            self.block();
+        } else {
+            self.expression();
+            self.check_return_type(self.expression_type());
+            self.emit_instruction(Instruction::Return);
+            cctx!(self).always_returns = true;
+        }
+
+        self.end_function();
     }
 
     pub fn unary(&mut self) {
         let op_type = self.tin.pre.kind;
+        let line = self.tin.pre.line;
 
         self.parse_precedence(Precedence::Unary);
+        let operand = self.expression_type();
 
         match op_type {
-            TokenType::Minus => self.emit_instruction(Instruction::Negate),
-            TokenType::Bang => self.emit_instruction(Instruction::Not),
+            TokenType::Minus => {
+                self.emit_instruction(Instruction::Negate);
+                self.check_type(|types| types.negate(line, &operand));
+            }
+            TokenType::Bang => {
+                self.emit_instruction(Instruction::Not);
+                self.set_type(Type::Bool);
+            }
             _ => (),
         };
     }
 
     pub fn binary(&mut self) {
         let op_type = self.tin.pre.kind;
+        let line = self.tin.pre.line;
+        let lhs = self.expression_type();
 
         let prule = parse_rule(op_type);
         self.parse_precedence(prule.curr_prec.next_greater());
+        let rhs = self.expression_type();
 
         match op_type {
             TokenType::Plus => self.emit_instruction(Instruction::Add),
@@ -303,6 +457,7 @@ impl<'a> Compiler<'a> {
             TokenType::Star => self.emit_instruction(Instruction::Multiply),
             TokenType::Slash => self.emit_instruction(Instruction::Divide),
             TokenType::EqualEqual => self.emit_instruction(Instruction::Equal),
+            TokenType::Is => self.emit_instruction(Instruction::Is),
             TokenType::BangEqual => {
                 self.emit_instruction(Instruction::Equal);
                 self.emit_instruction(Instruction::Not);
@@ -319,25 +474,328 @@ impl<'a> Compiler<'a> {
             }
             _ => panic!("Unsupported binary operator {:?}", op_type),
         }
-        // do nothing
+
+        self.check_type(|types| match op_type {
+            TokenType::Plus => types.arithmetic(line, &Instruction::Add, &lhs, &rhs),
+            TokenType::Minus => types.arithmetic(line, &Instruction::Subtract, &lhs, &rhs),
+            TokenType::Star => types.arithmetic(line, &Instruction::Multiply, &lhs, &rhs),
+            TokenType::Slash => types.arithmetic(line, &Instruction::Divide, &lhs, &rhs),
+            TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
+                types.comparison(line, &lhs, &rhs)
+            }
+            _ => Type::Bool,
+        });
     }
 
     pub fn string(&mut self) {
-        let lexeme_len = self.tin.pre.description.len();
-        let string = &self.tin.pre.description[1..lexeme_len - 1];
-        let string_ref = self.heap.intern_string(string);
-        self.emit_constant(Value::String(string_ref));
+        let constant_index = self.make_string_constant();
+        self.emit_instruction(Instruction::LoadConstant(constant_index));
+        self.set_type(Type::String);
+    }
+
+    fn make_string_constant(&mut self) -> ConstantIndex {
+        let string_ref = self.string_literal();
+        Self::make_constant(&mut cctx!(self), Value::String(string_ref), &self.tin)
+    }
+
+    /// Interns the value of the string literal just consumed.
+    fn string_literal(&mut self) -> Gc<LoxStr> {
+        let lexeme = self.tin.pre.description;
+        let (raw, lexeme) = match lexeme.strip_prefix('r') {
+            Some(lexeme) => (true, lexeme),
+            None => (false, lexeme),
+        };
+
+        let quote_len = if lexeme.len() >= 6 && lexeme.starts_with(TRIPLE_QUOTE) {
+            TRIPLE_QUOTE.len()
+        } else {
+            1
+        };
+        let body = &lexeme[quote_len..lexeme.len() - quote_len];
+
+        if raw {
+            self.heap.intern_string(body)
+        } else {
+            match unescape(body) {
+                Ok(string) => self.heap.intern_string(string),
+                Err(message) => {
+                    self.error_at_previous(&message);
+                    self.heap.intern_string(body)
+                }
+            }
+        }
+    }
+
+    /// Compiles `yield value`, which evaluates to the value passed to the generator's next resume.
+    pub fn yield_expression(&mut self) {
+        if !cctx!(self).function.is_generator {
+            self.error_at_previous("Can't use 'yield' outside of a generator.");
+        }
+
+        match self.tin.cur.kind {
+            TokenType::SemiColon
+            | TokenType::RightParen
+            | TokenType::RightBracket
+            | TokenType::Comma => self.emit_instruction(Instruction::Nil),
+            _ => self.expression(),
+        }
+
+        self.emit_instruction(Instruction::Yield);
+    }
+
+    pub fn list(&mut self, assign: bool) {
+        if assign && self.is_destructuring_assignment() {
+            self.destructuring_assignment();
+            return;
+        }
+
+        let mut item_count: usize = 0;
+
+        while !self.check(TokenType::RightBracket) {
+            self.expression();
+            item_count += 1;
+
+            if item_count > ArgCount::MAX as usize {
+                self.error_at_previous("Can't have more than 255 items in a list literal.");
+            }
+
+            if !self.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit_instruction(Instruction::BuildList(item_count as ArgCount));
+        self.set_type(Type::List);
+    }
+
+    /// Checks whether the '[' just consumed starts a pattern like `[a, b] = value` by scanning
+    /// ahead to its matching ']' and looking for a '=' after it.
+    fn is_destructuring_assignment(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut token = self.tin.cur;
+        let mut depth = 1;
+
+        loop {
+            match token.kind {
+                TokenType::LeftBracket => depth += 1,
+                TokenType::RightBracket => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return scanner.scan_token().kind == TokenType::Equal;
+                    }
+                }
+                TokenType::EOF => return false,
+                _ => {}
+            }
+
+            token = scanner.scan_token();
+        }
+    }
+
+    /// Compiles `[a, b, ...rest] = value` after the '['. The targets are assigned from the
+    /// last to the first and the whole value is left as the expression's result.
+    fn destructuring_assignment(&mut self) {
+        let (targets, has_rest) = self.pattern_names(TokenType::RightBracket, false);
+        self.consume(TokenType::Equal, "Expect '=' after pattern.");
+        self.expression();
+
+        self.emit_instruction(Instruction::Dup);
+        self.emit_unpack_list(targets.len(), has_rest);
+
+        for (target, _) in targets.iter().rev() {
+            let (_, set_op, mutable) = self.resolve_variable(target.description);
+            self.check_mutable(target.description, mutable);
+            self.emit_instruction(set_op);
+            self.emit_pop();
+        }
+    }
+
+    /// Parses the names of a `[...]` or `{...}` pattern up to and including `closing`, along
+    /// with whether the last one is a `...rest` name. Only list patterns can have a rest name.
+    /// With `declare` each name is declared as a variable and paired with its global's constant.
+    fn pattern_names(&mut self, closing: TokenType, declare: bool) -> (Vec<(Token<'a>, ConstantIndex)>, bool) {
+        let mut names = Vec::new();
+        let mut has_rest = false;
+
+        while !self.check(closing) {
+            if self.match_tt(TokenType::Ellipsis) {
+                if closing != TokenType::RightBracket {
+                    self.error_at_previous("Only list patterns can have a rest name.");
+                }
+                has_rest = true;
+            }
+
+            let global = if declare {
+                self.parse_variable("Expect variable name in pattern.")
+            } else {
+                self.consume(TokenType::Identifier, "Expect variable name in pattern.");
+                0
+            };
+            names.push((self.tin.pre, global));
+
+            if has_rest && !self.check(closing) {
+                self.error_at_current("A rest name must be the last one in a pattern.");
+            }
+
+            if !self.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+
+        if names.len() > ArgCount::MAX as usize {
+            self.error_at_previous("Can't have more than 255 names in a pattern.");
+        }
+
+        let message = if closing == TokenType::RightBracket {
+            "Expect ']' after list pattern."
+        } else {
+            "Expect '}' after field pattern."
+        };
+        self.consume(closing, message);
+
+        (names, has_rest)
+    }
+
+    fn emit_unpack_list(&mut self, name_count: usize, has_rest: bool) {
+        if has_rest {
+            self.emit_instruction(Instruction::UnpackListRest((name_count - 1) as ArgCount));
+        } else {
+            self.emit_instruction(Instruction::UnpackList(name_count as ArgCount));
+        }
+    }
+
+    pub fn index(&mut self, assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if assign && self.match_tt(TokenType::Equal) {
+            self.expression();
+            self.emit_instruction(Instruction::SetIndex);
+        } else if let Some(op) = self.compound_assignment_if(assign) {
+            // [list, index] -> [list, index, list, index] -> [list, index, value]
+            self.emit_instruction(Instruction::Over);
+            self.emit_instruction(Instruction::Over);
+            self.emit_instruction(Instruction::GetIndex);
+            self.expression();
+            self.emit_instruction(op);
+            self.emit_instruction(Instruction::SetIndex);
+        } else {
+            self.emit_instruction(Instruction::GetIndex);
+        }
+    }
+
+    pub fn conditional(&mut self) {
+        let else_jump = self.emit_jump(Instruction::jump_if_false_placeholder());
+        self.emit_pop();
+        self.expression();
+        let then_type = self.expression_type();
+        self.consume(TokenType::Colon, "Expect ':' after then branch of conditional expression.");
+
+        let end_jump = self.emit_jump(Instruction::jump_placeholder());
+        self.patch_fwd_jump(else_jump);
+        self.emit_pop();
+
+        // Parsing at the same precedence makes nested conditionals right associative.
+        self.parse_precedence(Precedence::Conditional);
+        self.patch_fwd_jump(end_jump);
+
+        let else_type = self.expression_type();
+        self.check_type(|types| types.join(&then_type, &else_type));
+    }
+
+    /// Consumes a compound assignment operator, returning the arithmetic it applies.
+    fn match_compound_assignment(&mut self) -> Option<Instruction> {
+        let op = match self.tin.cur.kind {
+            TokenType::PlusEqual => Instruction::Add,
+            TokenType::MinusEqual => Instruction::Subtract,
+            TokenType::StarEqual => Instruction::Multiply,
+            TokenType::SlashEqual => Instruction::Divide,
+            _ => return None,
+        };
+
+        self.advance();
+        Some(op)
+    }
+
+    /// Consumes a '++' or '--', returning the arithmetic it applies.
+    fn match_increment(&mut self) -> Option<Instruction> {
+        let op = match self.tin.cur.kind {
+            TokenType::PlusPlus => Instruction::Add,
+            TokenType::MinusMinus => Instruction::Subtract,
+            _ => return None,
+        };
+
+        self.advance();
+        Some(op)
+    }
+
+    /// Compiles '++target' and '--target' where the target is a variable optionally
+    /// followed by a chain of property accesses and method calls ending in a property.
+    pub fn prefix_update(&mut self) {
+        let op = if self.tin.pre.kind == TokenType::PlusPlus {
+            Instruction::Add
+        } else {
+            Instruction::Subtract
+        };
+
+        if self.match_tt(TokenType::This) {
+            if self.class_ctxs.is_empty() {
+                self.error_at_previous("Can't use 'this' outside of a class.");
+            }
+        } else {
+            self.consume(TokenType::Identifier, "Expect variable or property after prefix operator.");
+        }
+
+        let name = self.tin.pre.description;
+        let (get_op, set_op, mutable) = self.resolve_variable(name);
+        self.emit_instruction(get_op);
+
+        if !self.check(TokenType::Dot) {
+            self.check_mutable(name, mutable);
+            self.emit_constant(Value::Int(1));
+            self.emit_instruction(op);
+            self.emit_instruction(set_op);
+            return;
+        }
+
+        while self.match_tt(TokenType::Dot) {
+            self.consume(TokenType::Identifier, "Expect property name after '.'.");
+            let prop_in = self.make_identifier();
+
+            if self.match_tt(TokenType::LeftParen) {
+                let arg_count = self.argument_count();
+                self.emit_instruction(Instruction::Invoke(prop_in, arg_count));
+
+                if !self.check(TokenType::Dot) {
+                    self.error_at_current("Invalid target for prefix operator.");
+                    return;
+                }
+            } else if self.check(TokenType::Dot) {
+                self.emit_instruction(Instruction::GetProperty(prop_in));
+            } else {
+                self.emit_instruction(Instruction::Dup);
+                self.emit_instruction(Instruction::GetProperty(prop_in));
+                self.emit_constant(Value::Int(1));
+                self.emit_instruction(op);
+                self.emit_instruction(Instruction::SetProperty(prop_in));
+            }
+        }
     }
 
     pub fn and(&mut self) {
+        let lhs = self.expression_type();
         let patch_loc = self.emit_jump(Instruction::jump_if_false_placeholder());
         self.emit_pop();
         self.parse_precedence(Precedence::And);
 
         self.patch_fwd_jump(patch_loc);
+        let rhs = self.expression_type();
+        self.check_type(|types| types.join(&lhs, &rhs));
     }
 
     pub fn or(&mut self) {
+        let lhs = self.expression_type();
         let jmpif_patch_loc = self.emit_jump(Instruction::jump_if_false_placeholder());
         let jmp_patch_loc = self.emit_jump(Instruction::jump_placeholder());
 
@@ -346,26 +804,116 @@ impl<'a> Compiler<'a> {
 
         self.parse_precedence(Precedence::Or);
         self.patch_fwd_jump(jmp_patch_loc);
+
+        let rhs = self.expression_type();
+        self.check_type(|types| types.join(&lhs, &rhs));
+    }
+
+    /// Compiles `a ?? b`, which is `a` unless that's nil.
+    pub fn nil_coalescing(&mut self) {
+        let lhs = self.expression_type();
+        let else_jump = self.emit_jump(Instruction::jump_if_nil_placeholder());
+        let end_jump = self.emit_jump(Instruction::jump_placeholder());
+
+        self.patch_fwd_jump(else_jump);
+        self.emit_pop();
+
+        self.parse_precedence(Precedence::NilCoalescing);
+        self.patch_fwd_jump(end_jump);
+
+        let rhs = self.expression_type();
+        self.check_type(|types| match lhs {
+            Type::Optional(lhs) => types.join(&lhs, &rhs),
+            Type::Nil => rhs,
+            lhs => types.join(&lhs, &rhs),
+        });
+    }
+
+    /// Compiles `a?.b` and `a?.b(...)`. When `a` is nil the rest of the chain of property
+    /// accesses, calls and indexes after it is skipped and the whole chain is nil.
+    pub fn optional_dot(&mut self) {
+        let nil_jump = self.emit_jump(Instruction::jump_if_nil_placeholder());
+        self.dot(false);
+
+        while matches!(
+            self.tin.cur.kind,
+            TokenType::Dot | TokenType::QuestionDot | TokenType::LeftParen | TokenType::LeftBracket
+        ) {
+            self.advance();
+            let infix = parse_rule(self.tin.pre.kind).infix.unwrap();
+            infix(self, false);
+        }
+
+        self.patch_fwd_jump(nil_jump);
     }
 
     pub fn dot(&mut self, assign: bool) {
-        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        // Allows `Fiber.yield()` even though 'yield' is a keyword.
+        let object = self.expression_type();
+        if !self.match_tt(TokenType::Yield) {
+            self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        }
         let rhs_in = self.make_identifier();
+        let name = self.tin.pre.description;
+        let line = self.tin.pre.line;
 
         if assign && self.match_tt(TokenType::Equal) {
             self.expression();
             self.emit_instruction(Instruction::SetProperty(rhs_in));
+
+            let value = self.expression_type();
+            self.check_type(|types| {
+                types.set_property(line, &object, name, &value);
+                value
+            });
+        } else if let Some(op) = self.compound_assignment_if(assign) {
+            // [instance] -> [instance, instance] -> [instance, value] -> [instance, result]
+            self.emit_instruction(Instruction::Dup);
+            self.emit_instruction(Instruction::GetProperty(rhs_in));
+            self.expression();
+            let rhs = self.expression_type();
+            self.check_type(|types| {
+                let property = types.property(line, &object, name);
+                let value = types.arithmetic(line, &op, &property, &rhs);
+                types.set_property(line, &object, name, &value);
+                value
+            });
+
+            self.emit_instruction(op);
+            self.emit_instruction(Instruction::SetProperty(rhs_in));
+        } else if let Some(op) = self.match_increment() {
+            self.check_type(|types| {
+                let property = types.property(line, &object, name);
+                let value = types.arithmetic(line, &op, &property, &Type::Int);
+                types.set_property(line, &object, name, &value);
+                property
+            });
+
+            // Leaves the property's old value behind once the updated one is stored.
+            self.emit_instruction(Instruction::Dup);
+            self.emit_instruction(Instruction::GetProperty(rhs_in));
+            self.emit_instruction(Instruction::Swap);
+            self.emit_instruction(Instruction::Over);
+            self.emit_constant(Value::Int(1));
+            self.emit_instruction(op);
+            self.emit_instruction(Instruction::SetProperty(rhs_in));
+            self.emit_pop();
         } else if self.match_tt(TokenType::LeftParen) {
-            let arg_count = self.argument_count();
+            let (arg_count, arg_types) = self.arguments();
             self.emit_instruction(Instruction::Invoke(rhs_in, arg_count));
+            self.check_type(|types| types.invoke(line, &object, name, &arg_types));
         } else {
             self.emit_instruction(Instruction::GetProperty(rhs_in));
+            self.check_type(|types| types.property(line, &object, name));
         }
     }
 
     pub fn super_(&mut self) {
         if self.class_ctxs.is_empty() {
             self.error_at_previous("Can't use 'super' outside of a class.");
+        } else if self.class_ctxs.last().unwrap().is_trait {
+            // A trait's methods are shared by classes with different superclasses.
+            self.error_at_previous("Can't use 'super' in a trait.");
         } else if !self.class_ctxs.last().unwrap().has_superclass {
             self.error_at_previous("Can't use 'super' in a class with no superclass.");
         }
@@ -394,38 +942,116 @@ impl<'a> Compiler<'a> {
             self.error_at_previous("Can't use 'this' outside of a class.");
         }
         self.variable(false);
+
+        // Trait methods end up in classes the trait doesn't know about.
+        let ty = match self.class_ctxs.last() {
+            Some(class) if class.is_trait => Type::Any,
+            Some(class) if class.in_static_method => Type::Class(class.name.description),
+            Some(class) => Type::Instance(class.name.description),
+            None => Type::Any,
+        };
+        self.set_type(ty);
     }
 
     fn named_variable(&mut self, name: &str, assign: bool) {
+        let (get_op, set_op, mutable) = self.resolve_variable(name);
+
+        if assign && self.match_tt(TokenType::Equal) {
+            self.check_mutable(name, mutable);
+            self.expression();
+
+            self.emit_instruction(set_op);
+            self.assign_variable_type(&get_op, name, self.expression_type());
+        } else if let Some(op) = self.compound_assignment_if(assign) {
+            self.check_mutable(name, mutable);
+            self.emit_instruction(get_op);
+            self.expression();
+
+            let (old_value, rhs) = (self.read_variable_type(&get_op, name), self.expression_type());
+            let value = self.arithmetic_type(&op, &old_value, &rhs);
+            self.emit_instruction(op);
+            self.emit_instruction(set_op);
+            self.assign_variable_type(&get_op, name, value);
+        } else if let Some(op) = self.match_increment() {
+            self.check_mutable(name, mutable);
+            let old_value = self.read_variable_type(&get_op, name);
+            let value = self.arithmetic_type(&op, &old_value, &Type::Int);
+            self.assign_variable_type(&get_op, name, value);
+            self.set_type(old_value);
+
+            // The first load is the expression's value, the second one is updated and stored.
+            self.emit_instruction(get_op);
+            self.emit_instruction(get_op);
+            self.emit_constant(Value::Int(1));
+            self.emit_instruction(op);
+            self.emit_instruction(set_op);
+            self.emit_pop();
+        } else {
+            self.emit_instruction(get_op);
+            let var_type = self.read_variable_type(&get_op, name);
+            self.set_type(var_type);
+        }
+    }
+
+    fn compound_assignment_if(&mut self, assign: bool) -> Option<Instruction> {
+        if assign {
+            self.match_compound_assignment()
+        } else {
+            None
+        }
+    }
+
+    fn check_mutable(&mut self, name: &str, mutable: bool) {
+        if !mutable {
+            self.error_at_previous(&format!("Can't assign to constant '{}'.", name));
+        }
+    }
+
+    /// Returns the instructions that read and write the variable `name` from the current scope,
+    /// along with whether it may be assigned to.
+    fn resolve_variable(&mut self, name: &str) -> (Instruction, Instruction, bool) {
         let arg = self.resolve_local(name);
 
         let set_op;
         let get_op;
+        let mutable;
 
         if let Some(arg) = arg {
             get_op = Instruction::GetLocal(arg);
             set_op = Instruction::SetLocal(arg);
+            mutable = cctx!(self).stack_sim.locals[arg as usize].mutable;
         } else {
             let upvalue = self.resolve_upvalue(self.ctx_stk.len() - 1, name);
 
             if let Some(upvalue) = upvalue {
                 get_op = Instruction::GetUpvalue(upvalue);
                 set_op = Instruction::SetUpvalue(upvalue);
+                mutable = self.is_enclosing_local_mutable(name);
             } else {
                 let var_index = self.make_identifier_from_name(name);
                 set_op = Instruction::SetGlobal(var_index);
                 get_op = Instruction::GetGlobal(var_index);
+                // Constants defined by other modules or in earlier REPL lines are caught by SetGlobal.
+                mutable = !self.const_globals.contains(name);
             }
         }
 
-        if self.match_tt(TokenType::Equal) && assign {
-            self.expression();
+        (get_op, set_op, mutable)
+    }
 
-            self.emit_instruction(set_op);
-        } else {
-            self.emit_instruction(get_op);
-        }
- 
+    /// Looks up the local an upvalue named `name` captures, the same way `resolve_upvalue` does.
+    fn is_enclosing_local_mutable(&self, name: &str) -> bool {
+        self.ctx_stk[..self.ctx_stk.len() - 1]
+            .iter()
+            .rev()
+            .find_map(|ctx| {
+                ctx.stack_sim
+                    .locals
+                    .iter()
+                    .rev()
+                    .find(|local| local.name.description == name)
+            })
+            .map_or(true, |local| local.mutable)
     }
 
     pub fn variable(&mut self, assign: bool) {
@@ -475,41 +1101,96 @@ impl<'a> Compiler<'a> {
     }
 
     fn fun_declaration(&mut self) {
+        let is_generator = self.match_tt(TokenType::Star);
         let global = self.parse_variable("Expect function name.");
+        let name = self.tin.pre;
         cctx!(self).stack_sim.mark_initialized();
-        self.function(FunctionType::Function);
+
+        if is_generator {
+            self.generator(FunctionType::Function);
+        } else {
+            self.function(FunctionType::Function);
+        }
+        // Declarations are typed like annotations, so their callers in other functions are checked.
+        self.declare_type(name, Some(self.expression_type()), None);
         self.define_variable(global);
     }
 
-    fn new_context(
-        heap: &Heap,
-        tin: &TokenCursor,
-        function_type: FunctionType,
-    ) -> CompilerContext<'a> {
-        let name = heap.intern_string(tin.pre.description);
-        CompilerContext::new(function_type, name)
+    fn function(&mut self, function_type: FunctionType) {
+        self.begin_function(function_type, self.tin.pre.description);
+        self.function_body();
     }
 
-    fn function(&mut self, function_type: FunctionType) {
-        self.ctx_stk
-            .push(Self::new_context(&self.heap, &self.tin, function_type));
+    /// Compiles a function whose calls return a generator running its body.
+    fn generator(&mut self, function_type: FunctionType) {
+        self.begin_function(function_type, self.tin.pre.description);
+        cctx!(self).function.is_generator = true;
+        self.function_body();
+    }
+
+    fn function_body(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        self.parameters();
+        self.return_type_annotation();
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+
+        self.block();
+
+        self.end_function();
+    }
+
+    fn begin_function(&mut self, function_type: FunctionType, name: &str) {
+        let name = self.heap.intern_string(name);
+        let module = cctx!(self).errh.module;
+        self.ctx_stk.push(CompilerContext::new(function_type, name, module));
         self.curr_ctx += 1;
 
         cctx!(self).stack_sim.begin_scope();
+    }
 
-        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
-
+    /// Parses a parameter list up to and including the closing ')'.
+    fn parameters(&mut self) {
         if !self.check(TokenType::RightParen) {
             loop {
                 let ctx = &mut cctx!(self);
-                ctx.function.arity += 1;
-
-                if ctx.function.arity > 255 {
+                if ctx.function.max_arity() == 255 {
                     ctx.errh
                         .error_at_current(&self.tin, "Can't have more than 255 parameters.");
                 }
 
+                if self.match_tt(TokenType::Ellipsis) {
+                    let param_constant = self.parse_variable("Expect rest parameter name.");
+                    let name = self.tin.pre;
+                    let annotation = self.type_annotation();
+                    self.declare_type(name, annotation, Some(Type::List));
+                    self.define_variable(param_constant);
+                    cctx!(self).function.has_rest = true;
+
+                    if self.check(TokenType::Comma) {
+                        self.error_at_current("A rest parameter must be the last parameter.");
+                    }
+                    break;
+                }
+
                 let param_constant = self.parse_variable("Expect parameter name.");
+                let name = self.tin.pre;
+                let annotation = self.type_annotation();
+                let mut default_type = None;
+
+                if self.match_tt(TokenType::Equal) {
+                    self.default_parameter();
+                    default_type = Some(self.expression_type());
+                } else if cctx!(self).function.optional_arity > 0 {
+                    self.error_at_previous("A parameter without a default value can't follow one with a default.");
+                } else {
+                    cctx!(self).function.arity += 1;
+                }
+
+                // Without an annotation a parameter can be passed anything, whatever its default is.
+                let default_type = default_type.filter(|_| annotation.is_some());
+                cctx!(self).param_types.push(annotation.clone().unwrap_or_default());
+                self.declare_type(name, annotation, default_type);
                 self.define_variable(param_constant);
 
                 if !self.match_tt(TokenType::Comma) {
@@ -519,11 +1200,30 @@ impl<'a> Compiler<'a> {
         }
 
         self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+    }
 
-        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+    /// Compiles the default value of the parameter just declared into the function's
+    /// prologue. The vm leaves nil in the slot of every omitted argument.
+    fn default_parameter(&mut self) {
+        let ctx = &mut cctx!(self);
+        let param_index = ctx.function.max_arity() as ArgCount;
+        let slot = (ctx.stack_sim.size() - 1) as StackIndex;
+        ctx.function.optional_arity += 1;
 
-        self.block();
+        let skip_jump = self.emit_jump(Instruction::jump_if_arg_passed_placeholder(param_index));
+        self.expression();
+        self.emit_instruction(Instruction::SetLocal(slot));
+        self.emit_pop();
+        self.patch_fwd_jump(skip_jump);
+    }
 
+    fn end_function(&mut self) {
+        if !cctx!(self).always_returns {
+            // Falling off the end of the body returns nil.
+            self.join_returned_type(Type::Nil);
+        }
+
+        let signature = self.types.as_ref().map(|_| Rc::new(cctx!(self).signature()));
         let func_ptr = self.end_compile();
 
         let func_index = if let Some(func_ptr) = func_ptr {
@@ -533,15 +1233,38 @@ impl<'a> Compiler<'a> {
         };
 
         self.emit_instruction(Instruction::Closure(func_index));
+        if let Some(signature) = signature {
+            self.set_type(Type::Function(Some(signature)));
+        }
     }
 
     pub fn declaration(&mut self) {
+        cctx!(self).always_returns = false;
+
         if self.match_tt(TokenType::Var) {
             self.var_declaration()
-        } else if self.match_tt(TokenType::Fun) {
+        } else if self.match_tt(TokenType::Const) {
+            self.const_declaration()
+        } else if self.check(TokenType::Fun) && self.peek_next() != TokenType::LeftParen {
+            self.advance();
             self.fun_declaration();
         } else if self.match_tt(TokenType::Class) {
             self.class_declaration();
+        } else if self.match_tt(TokenType::Enum) {
+            self.enum_declaration();
+        } else if self.match_tt(TokenType::Trait) {
+            self.trait_declaration();
+        } else if self.check(TokenType::Identifier)
+            && self.tin.cur.description == TEST_KEYWORD
+            && self.peek_next() == TokenType::String
+        {
+            // 'test' is only a keyword before a test's name, so it's still usable as a name.
+            self.advance();
+            self.test_declaration();
+        } else if self.match_tt(TokenType::Import) {
+            self.import_declaration();
+        } else if self.match_tt(TokenType::From) {
+            self.from_import_declaration();
         } else {
             self.statement();
         }
@@ -559,6 +1282,57 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    /// Compiles `enum Name { Variant, Variant(field, ...), ... }`. The enum is a class and
+    /// the vm adds each variant to it in turn.
+    fn enum_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect enum name.");
+        let enum_name_in = self.make_identifier();
+        let enum_name = self.tin.pre.description;
+        self.declare_variable();
+        if let Some(types) = self.types.as_mut() {
+            types.declare_type_name(enum_name);
+        }
+
+        self.emit_instruction(Instruction::Enum(enum_name_in));
+        self.define_variable(enum_name_in);
+        self.named_variable(enum_name, false);
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before enum body.");
+
+        while !self.check(TokenType::RightBrace) {
+            self.consume(TokenType::Identifier, "Expect variant name.");
+            let variant_name_in = self.make_identifier();
+
+            let mut field_count: usize = 0;
+            if self.match_tt(TokenType::LeftParen) && !self.match_tt(TokenType::RightParen) {
+                loop {
+                    self.consume(TokenType::Identifier, "Expect field name.");
+                    let field_name_in = self.make_identifier();
+                    self.emit_instruction(Instruction::LoadConstant(field_name_in));
+                    field_count += 1;
+
+                    if !self.match_tt(TokenType::Comma) {
+                        break;
+                    }
+                }
+                self.consume(TokenType::RightParen, "Expect ')' after variant fields.");
+            }
+
+            if field_count > ArgCount::MAX as usize {
+                self.error_at_previous("Can't have more than 255 fields in a variant.");
+            }
+            self.emit_instruction(Instruction::EnumVariant(variant_name_in, field_count as ArgCount));
+
+            // A trailing comma is allowed after the last variant.
+            if !self.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after enum variants.");
+        self.emit_pop();
+    }
+
     fn class_declaration(&mut self) {
         self.consume(TokenType::Identifier, "Expect class name.");
         let class_name_in = self.make_identifier();
@@ -568,11 +1342,17 @@ impl<'a> Compiler<'a> {
         self.define_variable(class_name_in);
 
         let class_name_token = self.tin.pre;
+        self.declare_class_type(class_name_token, false);
         self.class_ctxs.push(ClassContext::new(&class_name_token));
 
         if self.match_tt(TokenType::Less) {
             self.consume(TokenType::Identifier , "Expect superclass name.");
 
+            let superclass = self.tin.pre.description;
+            if let Some(info) = self.types.as_mut().and_then(|types| types.classes.get_mut(class_name_token.description)) {
+                info.superclass = Some(superclass);
+            }
+
             self.begin_scope();
             let synthetic_token = self.synthetic_token("super");
             self.add_specified_local(synthetic_token);
@@ -591,13 +1371,17 @@ impl<'a> Compiler<'a> {
             self.class_ctxs.last_mut().unwrap().has_superclass = true;
         }
 
-        
+        self.with_clause(&class_name_token);
         self.named_variable(class_name_token.description, false);
 
         self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
 
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
-            self.method();
+            if self.check(TokenType::Identifier) && self.peek_next() == TokenType::Colon {
+                self.field_declaration();
+            } else {
+                self.method();
+            }
         }
 
         self.consume(TokenType::RightBrace, "Expect '}' after class body.");
@@ -611,37 +1395,267 @@ impl<'a> Compiler<'a> {
         self.class_ctxs.pop();
     }
 
+    /// Compiles `trait Name [with Trait, ...] { methods }`. Traits are compiled like classes
+    /// without a superclass, their methods get copied into the classes that use them.
+    fn trait_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect trait name.");
+        let trait_name_in = self.make_identifier();
+        self.declare_variable();
+
+        self.emit_instruction(Instruction::Trait(trait_name_in));
+        self.define_variable(trait_name_in);
+
+        let trait_name_token = self.tin.pre;
+        self.declare_class_type(trait_name_token, true);
+        let mut class_ctx = ClassContext::new(&trait_name_token);
+        class_ctx.is_trait = true;
+        self.class_ctxs.push(class_ctx);
+
+        self.with_clause(&trait_name_token);
+        self.named_variable(trait_name_token.description, false);
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before trait body.");
+
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if self.check(TokenType::Identifier) && self.tin.cur.description == "init" {
+                self.error_at_current("A trait can't have an initializer.");
+            }
+
+            self.method();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after trait body.");
+        self.emit_pop();
+        self.class_ctxs.pop();
+    }
+
+    /// Compiles the optional `with Trait, ...` list of a class or trait declaration.
+    fn with_clause(&mut self, name_token: &Token<'a>) {
+        if !self.match_tt(TokenType::With) {
+            return;
+        }
+
+        loop {
+            self.consume(TokenType::Identifier, "Expect trait name.");
+
+            if name_token.description == self.tin.pre.description {
+                self.error_at_previous("A class can't use itself as a trait.");
+            }
+
+            if let Some(types) = self.types.as_mut() {
+                types.use_trait(name_token.description, self.tin.pre.description);
+            }
+
+            self.variable(false);
+            self.named_variable(name_token.description, false);
+            self.emit_instruction(Instruction::UseTrait);
+
+            if !self.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+    }
+
     fn method(&mut self) {
+        let is_static = self.match_tt(TokenType::Class);
+        let is_generator = self.match_tt(TokenType::Star);
+        self.class_ctxs.last_mut().unwrap().in_static_method = is_static;
+
         self.consume(TokenType::Identifier, "Expect method name.");
         let name_in = self.make_identifier();
+        let name = self.tin.pre.description;
 
-        let function_type = if self.tin.pre.description == "init" {
-            FunctionType::Initializer
+        let instr = if is_generator {
+            if !is_static && name == "init" {
+                self.error_at_previous("An initializer can't be a generator.");
+            }
+
+            self.generator(FunctionType::Method);
+            if is_static { Instruction::StaticMethod(name_in) } else { Instruction::Method(name_in) }
+        } else if is_static {
+            // Static methods see the class itself as 'this'.
+            self.function(FunctionType::Method);
+            Instruction::StaticMethod(name_in)
+        } else if self.check(TokenType::LeftBrace) || self.check(TokenType::ThinArrow) {
+            self.getter();
+            Instruction::Getter(name_in)
         } else {
-            FunctionType::Method
+            let function_type = if name == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+
+            self.function(function_type);
+            Instruction::Method(name_in)
         };
 
-        self.function(function_type);
-        self.emit_instruction(Instruction::Method(name_in));
+        self.emit_instruction(instr);
+        self.record_method(name, instr);
+    }
+
+    /// Compiles `name: Type;` in a class body. Fields are still created by assigning to them,
+    /// the annotation only tells the type checker what they hold.
+    fn field_declaration(&mut self) {
+        self.advance();
+        let name = self.tin.pre.description;
+        let field_type = self.type_annotation();
+        self.consume(TokenType::SemiColon, "Expect ';' after field type.");
+
+        let class = self.class_ctxs.last().unwrap().name.description;
+        if let (Some(types), Some(field_type)) = (self.types.as_mut(), field_type) {
+            if let Some(info) = types.classes.get_mut(class) {
+                info.fields.insert(name, VarType::annotated(field_type));
+            }
+        }
+    }
+
+    fn import_declaration(&mut self) {
+        self.consume(TokenType::String, "Expect module path after 'import'.");
+        let path_in = self.make_string_constant();
+        self.emit_instruction(Instruction::ImportModule(path_in));
+
+        if self.check(TokenType::Identifier) && self.tin.cur.description == "as" {
+            self.advance();
+            let module_name_in = self.parse_variable("Expect module name after 'as'.");
+            self.define_variable(module_name_in);
+        } else {
+            // Imported only for the side effects of running it.
+            self.emit_pop();
+        }
+
+        self.consume(TokenType::SemiColon, "Expect ';' after import.");
+    }
+
+    fn from_import_declaration(&mut self) {
+        self.consume(TokenType::String, "Expect module path after 'from'.");
+        let path_in = self.make_string_constant();
+        self.consume(TokenType::Import, "Expect 'import' after module path.");
+
+        loop {
+            let var_name_index = self.parse_variable("Expect name to import.");
+            let member_in = self.make_identifier();
+            // Imported classes can be used in annotations, though their members aren't checked.
+            if let Some(types) = self.types.as_mut() {
+                types.declare_type_name(self.tin.pre.description);
+            }
+
+            // Modules are cached after their first import so reloading one per name is cheap.
+            self.emit_instruction(Instruction::ImportModule(path_in));
+            self.emit_instruction(Instruction::GetProperty(member_in));
+            self.define_variable(var_name_index);
+
+            if !self.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenType::SemiColon, "Expect ';' after imported names.");
+    }
+
+    fn getter(&mut self) {
+        if self.tin.pre.description == "init" {
+            self.error_at_previous("An initializer can't be a getter.");
+        }
+
+        self.begin_function(FunctionType::Method, self.tin.pre.description);
+        self.return_type_annotation();
+        self.consume(TokenType::LeftBrace, "Expect '{' before getter body.");
+        self.block();
+        self.end_function();
     }
 
     pub fn var_declaration(&mut self) {
+        if self.match_tt(TokenType::LeftBracket) {
+            self.destructuring_declaration(TokenType::RightBracket);
+            return;
+        } else if self.match_tt(TokenType::LeftBrace) {
+            self.destructuring_declaration(TokenType::RightBrace);
+            return;
+        }
+
         let var_name_index = self.parse_variable("Expect variable name.");
+        let name = self.tin.pre;
+        let annotation = self.type_annotation();
 
-        if self.match_tt(TokenType::Equal) {
+        let value_type = if self.match_tt(TokenType::Equal) {
             self.expression();
+            Some(self.expression_type())
         } else {
             self.emit_instruction(Instruction::Nil);
-        }
+            None
+        };
 
         self.consume(
             TokenType::SemiColon,
             "Expect ';' after variable declaration.",
         );
 
+        self.declare_type(name, annotation, value_type);
         self.define_variable(var_name_index);
     }
 
+    /// Compiles `var [a, b, ...rest] = value;` or `var {x, y} = value;` after the opening
+    /// bracket. Like any var declaration the names are declared before the value is compiled,
+    /// and the unpacked values end up in the declared locals' slots.
+    fn destructuring_declaration(&mut self, closing: TokenType) {
+        let (names, has_rest) = self.pattern_names(closing, true);
+
+        self.consume(TokenType::Equal, "Expect '=' after pattern.");
+        self.expression();
+        self.consume(
+            TokenType::SemiColon,
+            "Expect ';' after variable declaration.",
+        );
+
+        if closing == TokenType::RightBracket {
+            self.emit_unpack_list(names.len(), has_rest);
+        } else {
+            for (name, _) in names.iter() {
+                let name_in = self.make_identifier_from_name(name.description);
+                self.emit_instruction(Instruction::LoadConstant(name_in));
+            }
+            self.emit_instruction(Instruction::UnpackFields(names.len() as ArgCount));
+        }
+
+        let ctx = &mut cctx!(self);
+        if ctx.stack_sim.scope_depth > 0 {
+            let depth = ctx.stack_sim.scope_depth;
+            for local in ctx.stack_sim.locals.iter_mut().rev().take(names.len()) {
+                local.depth = depth;
+            }
+        } else {
+            // The last value is on top of the stack.
+            for (_, global) in names.into_iter().rev() {
+                self.emit_instruction(Instruction::DefineGlobal(global));
+            }
+        }
+    }
+
+    fn const_declaration(&mut self) {
+        let name_index = self.parse_variable("Expect constant name.");
+        let name_token = self.tin.pre;
+        let name = name_token.description;
+        let annotation = self.type_annotation();
+
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::SemiColon,
+            "Expect ';' after constant declaration.",
+        );
+        self.declare_type(name_token, annotation, Some(self.expression_type()));
+
+        let ctx = &mut cctx!(self);
+        if ctx.stack_sim.scope_depth > 0 {
+            ctx.stack_sim.mark_initialized();
+            ctx.stack_sim.locals.last_mut().unwrap().mutable = false;
+        } else {
+            self.const_globals.insert(name);
+            self.emit_instruction(Instruction::DefineConstGlobal(name_index));
+        }
+    }
+
     fn define_variable(&mut self, global: ConstantIndex) {
         let ctx = &mut cctx!(self);
         if ctx.stack_sim.scope_depth > 0 {
@@ -694,26 +1708,36 @@ impl<'a> Compiler<'a> {
     }
 
     fn return_statement(&mut self) {
-        if let FunctionType::Script = cctx!(self).function_type {
+        if let FunctionType::Script | FunctionType::Module = cctx!(self).function_type {
             cctx!(self)
                 .errh
                 .error_at_previous(&self.tin, "Can't return from top-level code.");
         }
 
         if self.match_tt(TokenType::SemiColon) {
+            self.check_return_type(Type::Nil);
             self.emit_return();
         } else {
             if cctx!(self).function_type == FunctionType::Initializer {
                 self.error_at_previous("Can't return a value from an initializer.");
             }
 
+            if cctx!(self).function.is_generator {
+                self.error_at_previous("Can't return a value from a generator.");
+            }
+
             self.expression();
+            self.check_return_type(self.expression_type());
             self.consume(TokenType::SemiColon, "Expect ';' after return value.");
             self.emit_instruction(Instruction::Return);
         }
+
+        cctx!(self).always_returns = true;
     }
 
     pub fn statement(&mut self) {
+        let kind = self.tin.cur.kind;
+
         if self.match_tt(TokenType::Print) {
             self.print_statement();
         } else if self.match_tt(TokenType::If) {
@@ -724,6 +1748,14 @@ impl<'a> Compiler<'a> {
             self.while_statement();
         } else if self.match_tt(TokenType::For) {
             self.for_statement();
+        } else if self.match_tt(TokenType::Match) {
+            self.match_statement();
+        } else if self.match_tt(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_tt(TokenType::Assert) {
+            self.assert_statement();
+        } else if self.match_tt(TokenType::Try) {
+            self.try_statement();
         } else if self.match_tt(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -733,10 +1765,20 @@ impl<'a> Compiler<'a> {
             self.consume(TokenType::SemiColon, "Expect ';' after value.");
             self.emit_pop();
         }
+
+        // Other statements can finish normally even when a return in their body doesn't.
+        if !matches!(kind, TokenType::If | TokenType::Return | TokenType::Throw | TokenType::LeftBrace) {
+            cctx!(self).always_returns = false;
+        }
     }
 
     fn for_statement(&mut self) {
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.check(TokenType::Identifier) && self.peek_next() == TokenType::In {
+            self.for_in_statement();
+            return;
+        }
+
         self.begin_scope();
 
         if self.match_tt(TokenType::SemiColon) {
@@ -785,6 +1827,353 @@ impl<'a> Compiler<'a> {
         self.end_scope();
     }
 
+    /// Compiles the rest of `for (name in iterable) statement`. The loop variable is declared
+    /// in its own scope so closures in the body capture a distinct variable each iteration.
+    fn for_in_statement(&mut self) {
+        self.advance();
+        let name = self.tin.pre;
+        self.consume(TokenType::In, "Expect 'in' after loop variable.");
+        self.begin_scope();
+
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after for-in clause.");
+        self.emit_instruction(Instruction::IterInit);
+
+        // The iterator and how far it has got live in hidden locals.
+        let iterator_token = self.synthetic_token("for iterator");
+        self.add_specified_local(iterator_token);
+        self.define_variable(0);
+        let iterator = (cctx!(self).stack_sim.size() - 1) as StackIndex;
+
+        self.emit_constant(Value::Int(0));
+        let state_token = self.synthetic_token("for state");
+        self.add_specified_local(state_token);
+        self.define_variable(0);
+
+        let loop_start = cchunk!(self).next_byte_index();
+        self.emit_instruction(Instruction::IterNext(iterator));
+        let exit_jump = self.emit_jump(Instruction::jump_if_iter_done_placeholder(iterator));
+
+        self.begin_scope();
+        self.add_specified_local(name);
+        self.define_variable(0);
+        self.statement();
+        self.end_scope();
+
+        self.emit_back_jump(loop_start);
+        self.patch_fwd_jump(exit_jump);
+        self.end_scope();
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::SemiColon, "Expect ';' after thrown value.");
+        self.emit_instruction(Instruction::Throw);
+        cctx!(self).always_returns = true;
+    }
+
+    /// Compiles `assert condition[, message];`. A falsey condition throws an error quoting
+    /// the condition's source, and the message is only evaluated when that happens.
+    fn assert_statement(&mut self) {
+        let first_token = self.tin.cur;
+        self.expression();
+        let source = self.scanner.source_between(&first_token, &self.tin.pre);
+        let source_in = self.make_identifier_from_name(source);
+
+        let fail_jump = self.emit_jump(Instruction::jump_if_false_placeholder());
+        self.emit_pop();
+        let end_jump = self.emit_jump(Instruction::jump_placeholder());
+
+        self.patch_fwd_jump(fail_jump);
+        self.emit_pop();
+        if self.match_tt(TokenType::Comma) {
+            self.expression();
+        } else {
+            self.emit_instruction(Instruction::Nil);
+        }
+        self.emit_instruction(Instruction::AssertFailed(source_in));
+
+        self.patch_fwd_jump(end_jump);
+        self.consume(TokenType::SemiColon, "Expect ';' after assertion.");
+    }
+
+    /// Compiles `test "name" { ... }` into a function named after the test. Scripts hand it
+    /// to the vm, which only keeps it when running tests, while imported modules drop it.
+    fn test_declaration(&mut self) {
+        self.consume(TokenType::String, "Expect test name.");
+        let name = self.string_literal();
+
+        let ctx = &cctx!(self);
+        let in_module = ctx.function_type == FunctionType::Module;
+        if !(in_module || ctx.function_type == FunctionType::Script) || ctx.stack_sim.scope_depth > 0 {
+            self.error_at_previous("Test blocks must be at the top level of a script.");
+        }
+
+        self.begin_function(FunctionType::Function, name.as_str());
+        self.consume(TokenType::LeftBrace, "Expect '{' before test body.");
+        self.block();
+        self.end_function();
+
+        if in_module {
+            self.emit_pop();
+        } else {
+            self.emit_instruction(Instruction::Test);
+        }
+    }
+
+    fn try_statement(&mut self) {
+        let handler_loc = self.emit_jump(Instruction::push_handler_placeholder());
+
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+
+        // Completing the try block normally leaves its handler behind.
+        self.emit_instruction(Instruction::PopHandler);
+
+        let has_catch = self.match_tt(TokenType::Catch);
+        if has_catch {
+            let catch_exit_jump = self.emit_jump(Instruction::jump_placeholder());
+            self.patch_fwd_operand(handler_loc, CATCH_OPERAND);
+
+            // The vm pushes the thrown value before jumping to the catch block.
+            self.begin_scope();
+            if self.match_tt(TokenType::LeftParen) {
+                self.parse_variable("Expect exception variable name.");
+                self.define_variable(0);
+                self.consume(TokenType::RightParen, "Expect ')' after exception variable.");
+            } else {
+                self.emit_pop();
+            }
+
+            self.consume(TokenType::LeftBrace, "Expect '{' after catch clause.");
+            self.block();
+            self.end_scope();
+
+            // The vm pops the handler when it enters the catch block, unless there's a finally
+            // block that still has to run if the catch block throws or returns.
+            if self.check(TokenType::Finally) {
+                self.emit_instruction(Instruction::PopHandler);
+            }
+
+            self.patch_fwd_jump(catch_exit_jump);
+        }
+
+        if self.match_tt(TokenType::Finally) {
+            // Normal completion falls through into the finally block, while thrown values
+            // and returns enter it at the handler's finally offset with their own completion.
+            self.emit_instruction(Instruction::EnterFinally);
+            self.patch_fwd_operand(handler_loc, FINALLY_OPERAND);
+
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+
+            self.emit_instruction(Instruction::EndFinally);
+        } else if !has_catch {
+            self.error_at_current("Expect 'catch' or 'finally' after try block.");
+        }
+    }
+
+    fn match_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'match'.");
+        self.begin_scope();
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after match value.");
+
+        // The matched value lives in a hidden local so every arm can reload it.
+        let subject_token = self.synthetic_token("match");
+        self.add_specified_local(subject_token);
+        self.define_variable(0);
+        let subject = (cctx!(self).stack_sim.size() - 1) as StackIndex;
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before match arms.");
+
+        let mut end_jumps = Vec::new();
+        let mut has_default = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if has_default {
+                self.error_at_current("The default arm must be the last arm in a match.");
+            }
+
+            // A guarded '_' arm can still fall through to later arms.
+            has_default = self.check(TokenType::Identifier)
+                && self.tin.cur.description == MATCH_DEFAULT_PATTERN
+                && self.peek_next() != TokenType::If;
+            end_jumps.push(self.match_arm(subject));
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms.");
+
+        for end_jump in end_jumps {
+            self.patch_fwd_jump(end_jump);
+        }
+        self.end_scope();
+    }
+
+    /// Compiles `pattern [if guard] => statement`, returning the jump out of the match
+    /// taken after the arm's statement runs.
+    fn match_arm(&mut self, subject: StackIndex) -> usize {
+        self.begin_scope();
+
+        // Names bound by the pattern, with the variant field each one reads or None for
+        // a name bound to the whole value.
+        let mut bindings: Vec<(Token<'a>, Option<ArgCount>)> = Vec::new();
+        let mut fail_jump = None;
+
+        if self.check(TokenType::Identifier) && self.tin.cur.description == MATCH_DEFAULT_PATTERN {
+            self.advance();
+        } else {
+            let mut body_jumps = Vec::new();
+
+            loop {
+                self.emit_instruction(Instruction::GetLocal(subject));
+
+                if self.match_tt(TokenType::Identifier) && self.check(TokenType::Dot) {
+                    self.variant_pattern(&mut bindings);
+                } else if self.tin.pre.kind == TokenType::Identifier {
+                    self.variable(false);
+                    self.emit_instruction(Instruction::IsInstance);
+
+                    if self.match_tt(TokenType::Identifier) {
+                        bindings.push((self.tin.pre, None));
+                    }
+                } else {
+                    self.literal_pattern();
+                    self.emit_instruction(Instruction::Equal);
+                }
+
+                if !self.match_tt(TokenType::Comma) {
+                    break;
+                }
+
+                if !bindings.is_empty() {
+                    self.error_at_previous("Can't bind a name in a pattern with alternatives.");
+                }
+
+                // A failed alternative moves on to the next one, a successful one skips to the arm.
+                let next_alternative = self.emit_jump(Instruction::jump_if_false_placeholder());
+                self.emit_pop();
+                body_jumps.push(self.emit_jump(Instruction::jump_placeholder()));
+                self.patch_fwd_jump(next_alternative);
+                self.emit_pop();
+            }
+
+            fail_jump = Some(self.emit_jump(Instruction::jump_if_false_placeholder()));
+            self.emit_pop();
+
+            for body_jump in body_jumps {
+                self.patch_fwd_jump(body_jump);
+            }
+        }
+
+        for (binding, field) in bindings.iter() {
+            self.emit_instruction(Instruction::GetLocal(subject));
+            if let Some(field) = field {
+                self.emit_instruction(Instruction::GetVariantField(*field));
+            }
+            self.add_specified_local(*binding);
+            self.define_variable(0);
+        }
+
+        let mut guard_jump = None;
+        if self.match_tt(TokenType::If) {
+            self.expression();
+            guard_jump = Some(self.emit_jump(Instruction::jump_if_false_placeholder()));
+            self.emit_pop();
+        }
+
+        self.consume(TokenType::Arrow, "Expect '=>' after match pattern.");
+        self.statement();
+
+        let locals = &cctx!(self).stack_sim.locals;
+        let bindings_captured: Vec<bool> = locals[locals.len() - bindings.len()..]
+            .iter()
+            .map(|local| local.captured)
+            .collect();
+        self.end_scope();
+        let end_jump = self.emit_jump(Instruction::jump_placeholder());
+
+        if let Some(guard_jump) = guard_jump {
+            // A failed guard leaves the bindings under its condition.
+            self.patch_fwd_jump(guard_jump);
+            self.emit_pop();
+            for captured in bindings_captured.into_iter().rev() {
+                if captured {
+                    self.emit_instruction(Instruction::CloseUpvalue);
+                } else {
+                    self.emit_pop();
+                }
+            }
+
+            if let Some(pattern_jump) = fail_jump {
+                let next_arm = self.emit_jump(Instruction::jump_placeholder());
+                self.patch_fwd_jump(pattern_jump);
+                self.emit_pop();
+                self.patch_fwd_jump(next_arm);
+            }
+        } else if let Some(pattern_jump) = fail_jump {
+            self.patch_fwd_jump(pattern_jump);
+            self.emit_pop();
+        }
+
+        end_jump
+    }
+
+    /// Compiles `Enum.Variant` or `Enum.Variant(name, ...)` after the enum's name, leaving
+    /// whether the value on the stack matches. `_` skips a field instead of binding it.
+    fn variant_pattern(&mut self, bindings: &mut Vec<(Token<'a>, Option<ArgCount>)>) {
+        self.variable(false);
+        self.consume(TokenType::Dot, "Expect '.' after enum name.");
+        self.consume(TokenType::Identifier, "Expect variant name after '.'.");
+        let variant_name_in = self.make_identifier();
+        self.emit_instruction(Instruction::GetProperty(variant_name_in));
+
+        if !self.match_tt(TokenType::LeftParen) {
+            // Variants without fields are a single instance.
+            self.emit_instruction(Instruction::Equal);
+            return;
+        }
+
+        let mut field: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.consume(TokenType::Identifier, "Expect field binding name.");
+                if self.tin.pre.description != MATCH_DEFAULT_PATTERN {
+                    bindings.push((self.tin.pre, Some(field as ArgCount)));
+                }
+                field += 1;
+
+                if !self.match_tt(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after field bindings.");
+
+        if field > ArgCount::MAX as usize {
+            self.error_at_previous("Can't have more than 255 fields in a pattern.");
+        }
+        self.emit_instruction(Instruction::IsVariant(field as ArgCount));
+    }
+
+    fn literal_pattern(&mut self) {
+        match self.tin.cur.kind {
+            TokenType::Number
+            | TokenType::String
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Nil => self.parse_precedence(Precedence::Unary),
+            TokenType::Minus if self.peek_next() == TokenType::Number => {
+                self.parse_precedence(Precedence::Unary)
+            }
+            _ => self.error_at_current("Expect literal, class or '_' pattern."),
+        }
+    }
+
     pub fn while_statement(&mut self) {
         let loop_jump = cchunk!(self).next_byte_index();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
@@ -824,30 +2213,40 @@ impl<'a> Compiler<'a> {
         // Pop if condition expression from stack.
         self.emit_pop();
         self.statement();
+        let then_returns = cctx!(self).always_returns;
         // Jump to avoid potential else block bytecode coming up next.
         let else_patch_loc = self.emit_jump(Instruction::jump_placeholder());
 
         self.patch_fwd_jump(patch_loc);
 
-        if self.match_tt(TokenType::Else) {
-            // Pop if condition expression from stack.
-            // The previously written pop op in this function won't work since it's in the if then block.
-            self.emit_pop();
+        // Pop if condition expression from stack.
+        // The previously written pop op in this function won't work since it's in the if then block.
+        self.emit_pop();
 
+        if self.match_tt(TokenType::Else) {
             self.statement();
+            cctx!(self).always_returns &= then_returns;
+        } else {
+            cctx!(self).always_returns = false;
         }
         self.patch_fwd_jump(else_patch_loc);
     }
 
     fn patch_fwd_jump(&mut self, patch_loc: usize) {
+        // + 1 ensures that the ByteCodeIndex is written into the jump offset
+        // not overrwriting in Instr Opcode
+        self.patch_fwd_operand(patch_loc, 1);
+    }
+
+    /// Patches the operand at `operand_offset` bytes into the instruction at `patch_loc`
+    /// with the distance from that instruction to the next byte to be emitted.
+    fn patch_fwd_operand(&mut self, patch_loc: usize, operand_offset: usize) {
         let patch: Result<ByteCodeOffset, _> =
             (cchunk!(self).next_byte_index() - patch_loc).try_into();
 
         if let Ok(patch) = patch {
             cchunk!(self)
-                // + 1 ensures that the ByteCodeIndex is written into the jump offset
-                // not overrwriting in Instr Opcode
-                .patch_bytecode_index(patch_loc + 1, patch as ByteCodeOffset);
+                .patch_bytecode_index(patch_loc + operand_offset, patch as ByteCodeOffset);
         } else {
             cctx!(self)
                 .errh
@@ -886,9 +2285,12 @@ impl<'a> Compiler<'a> {
     }
 
     pub fn block(&mut self) {
+        let mut always_returns = false;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
             self.declaration();
+            always_returns |= cctx!(self).always_returns;
         }
+        cctx!(self).always_returns = always_returns;
 
         self.consume(TokenType::RightBrace, "Expect '}' after block.");
     }
@@ -909,30 +2311,241 @@ impl<'a> Compiler<'a> {
 
     fn parse_precedence(&mut self, prec_bound: Precedence) {
         let ParseRule {
-            prefix: prefix_fn,
-            curr_prec,
-            ..
+            prefix: prefix_fn, ..
         } = parse_rule(self.tin.cur.kind);
-        let can_assign = *curr_prec <= Precedence::Assignment;
+        let can_assign = prec_bound <= Precedence::Assignment;
+        let enclosing = self.types.as_mut().map(TypeChecker::begin_expression);
 
         if let Some(prefix_fn) = prefix_fn {
             self.advance();
-            prefix_fn(self, can_assign);
+            self.typed_operand(|this| prefix_fn(this, can_assign));
         } else {
             self.error_at_previous("Unexpected expression.");
             self.advance();
-            return;
         }
 
         loop {
             let prule = parse_rule(self.tin.cur.kind);
-            if prec_bound <= prule.curr_prec {
+            if prefix_fn.is_some() && prec_bound <= prule.curr_prec {
                 self.advance();
-                (prule.infix.unwrap())(self, can_assign);
+                self.typed_operand(|this| (prule.infix.unwrap())(this, can_assign));
             } else {
                 break;
             }
         }
+
+        if let (Some(types), Some(enclosing)) = (self.types.as_mut(), enclosing) {
+            types.end_expression(enclosing);
+        }
+
+        if prefix_fn.is_some() && can_assign && (self.match_tt(TokenType::Equal) || self.match_compound_assignment().is_some()) {
+            self.error_at_previous("Invalid assignment target.");
+        }
+    }
+
+    /// Compiles an operand with `parse`, which sets its type when it can work one out.
+    fn typed_operand(&mut self, parse: impl FnOnce(&mut Self)) {
+        if let Some(types) = self.types.as_mut() {
+            types.begin_operand();
+        }
+
+        parse(self);
+
+        if let Some(types) = self.types.as_mut() {
+            types.end_operand();
+        }
+    }
+
+    fn set_type(&mut self, ty: Type<'a>) {
+        if let Some(types) = self.types.as_mut() {
+            types.set(ty);
+        }
+    }
+
+    /// Sets the type of the expression just compiled to the one `check` works out.
+    fn check_type(&mut self, check: impl FnOnce(&mut TypeChecker<'a>) -> Type<'a>) {
+        if let Some(types) = self.types.as_mut() {
+            let ty = check(types);
+            types.set(ty);
+        }
+    }
+
+    /// The type of the expression compiled last, which is Any when types aren't checked.
+    fn expression_type(&self) -> Type<'a> {
+        self.types.as_ref().map_or(Type::Any, TypeChecker::expression_type)
+    }
+
+    fn arithmetic_type(&mut self, op: &Instruction, lhs: &Type<'a>, rhs: &Type<'a>) -> Type<'a> {
+        let line = self.tin.pre.line;
+        self.types.as_mut().map_or(Type::Any, |types| types.arithmetic(line, op, lhs, rhs))
+    }
+
+    /// Parses an optional `: Type` annotation after a variable or parameter name.
+    fn type_annotation(&mut self) -> Option<Type<'a>> {
+        if self.match_tt(TokenType::Colon) {
+            Some(self.type_name())
+        } else {
+            None
+        }
+    }
+
+    /// Parses an optional `-> Type` annotation after a function's parameters.
+    fn return_type_annotation(&mut self) {
+        if self.match_tt(TokenType::ThinArrow) {
+            cctx!(self).return_type = Some(self.type_name());
+        }
+    }
+
+    fn type_name(&mut self) -> Type<'a> {
+        self.consume(TokenType::Identifier, "Expect type name.");
+        let name = self.tin.pre;
+        let ty = self.types.as_mut().map_or(Type::Any, |types| types.resolve(name));
+
+        if self.match_tt(TokenType::Question) {
+            Type::Optional(Box::new(ty))
+        } else {
+            ty
+        }
+    }
+
+    /// Gives the variable just declared its type, checking its initial value against its annotation.
+    fn declare_type(&mut self, name: Token<'a>, annotation: Option<Type<'a>>, value: Option<Type<'a>>) {
+        let types = match self.types.as_mut() {
+            Some(types) => types,
+            None => return,
+        };
+
+        let var_type = match (annotation, value) {
+            (Some(annotation), Some(value)) => {
+                let description = format!("variable '{}'", name.description);
+                types.check_assignable(name.line, &annotation, &value, &description);
+                VarType::annotated(annotation)
+            }
+            (Some(annotation), None) => VarType::annotated(annotation),
+            (None, value) => VarType::inferred(value.unwrap_or_default()),
+        };
+
+        let ctx = &mut cctx!(self);
+        if ctx.stack_sim.scope_depth > 0 {
+            ctx.stack_sim.locals.last_mut().unwrap().var_type = var_type;
+        } else {
+            types.globals.insert(name.description, var_type);
+        }
+    }
+
+    /// Registers a class or trait with the type checker, and gives a class' variable its type.
+    fn declare_class_type(&mut self, name: Token<'a>, is_trait: bool) {
+        if let Some(types) = self.types.as_mut() {
+            types.declare_type_name(name.description);
+            types.classes.insert(name.description, ClassInfo { is_trait, ..ClassInfo::default() });
+        }
+
+        if !is_trait {
+            self.declare_type(name, Some(Type::Class(name.description)), None);
+        }
+    }
+
+    /// Adds the method that `instr` defines on the class being compiled to what the type checker knows.
+    fn record_method(&mut self, name: &'a str, instr: Instruction) {
+        let class = self.class_ctxs.last().unwrap().name.description;
+        let signature = match self.expression_type() {
+            Type::Function(Some(signature)) => signature,
+            _ => return,
+        };
+        let types = self.types.as_mut().unwrap();
+
+        match instr {
+            Instruction::Getter(_) => {
+                if let Some(info) = types.classes.get_mut(class) {
+                    info.getters.insert(name, signature.return_type.clone());
+                }
+            }
+            Instruction::StaticMethod(_) => types.add_method(class, name, signature, true),
+            _ => types.add_method(class, name, signature, false),
+        }
+    }
+
+    /// The type of the variable that `get_op` reads, when it's known.
+    fn variable_type(&mut self, get_op: &Instruction, name: &str) -> Option<&mut VarType<'a>> {
+        match get_op {
+            Instruction::GetLocal(slot) => Some(&mut cctx!(self).stack_sim.locals[*slot as usize].var_type),
+            Instruction::GetUpvalue(_) => {
+                let enclosing = self.ctx_stk.len() - 1;
+                self.ctx_stk[..enclosing]
+                    .iter_mut()
+                    .rev()
+                    .find_map(|ctx| {
+                        ctx.stack_sim
+                            .locals
+                            .iter_mut()
+                            .rev()
+                            .find(|local| local.name.description == name)
+                    })
+                    .map(|local| &mut local.var_type)
+            }
+            _ => self.types.as_mut()?.globals.get_mut(name),
+        }
+    }
+
+    fn read_variable_type(&mut self, get_op: &Instruction, name: &str) -> Type<'a> {
+        // A function can run after code compiled later has widened an inferred global or
+        // enclosing variable, so it only trusts their types when they're annotated.
+        let declared_elsewhere = !matches!(get_op, Instruction::GetLocal(_)) && self.ctx_stk.len() > 1;
+
+        match self.variable_type(get_op, name) {
+            Some(var_type) if var_type.annotated || !declared_elsewhere => var_type.ty.clone(),
+            _ => Type::Any,
+        }
+    }
+
+    /// Checks a value assigned to the variable that `get_op` reads, which is also the
+    /// assignment's value.
+    fn assign_variable_type(&mut self, get_op: &Instruction, name: &str, value: Type<'a>) {
+        if self.types.is_none() {
+            return;
+        }
+
+        let line = self.tin.pre.line;
+        if let Some(var_type) = self.variable_type(get_op, name).cloned() {
+            let description = format!("variable '{}'", name);
+            let var_type = self.types.as_mut().unwrap().assign(line, &var_type, &value, &description);
+            *self.variable_type(get_op, name).unwrap() = var_type;
+        }
+
+        self.set_type(value);
+    }
+
+    /// Checks a value the current function returns against its return type annotation.
+    fn check_return_type(&mut self, value: Type<'a>) {
+        let line = self.tin.pre.line;
+        let types = match self.types.as_mut() {
+            Some(types) => types,
+            None => return,
+        };
+
+        let ctx = &mut cctx!(self);
+        if let Some(return_type) = &ctx.return_type {
+            if !types.is_assignable(return_type, &value) {
+                let message = format!("Expected '{}' to return {} but got {}.", ctx.function.name, return_type, value);
+                types.error(line, message);
+            }
+        }
+
+        self.join_returned_type(value);
+    }
+
+    /// Joins a type the current function can return into its inferred return type.
+    fn join_returned_type(&mut self, value: Type<'a>) {
+        let types = match self.types.as_ref() {
+            Some(types) => types,
+            None => return,
+        };
+
+        let ctx = &mut cctx!(self);
+        ctx.returned_type = Some(match &ctx.returned_type {
+            Some(returned_type) => types.join(returned_type, &value),
+            None => value,
+        });
     }
 }
 
@@ -943,6 +2556,44 @@ pub struct StackSim<'a> {
 
 const LOCALS_MAX_CAPACITY: usize = u8::MAX as usize;
 
+const ANONYMOUS_FUNCTION_NAME: &str = "anonymous";
+
+const MATCH_DEFAULT_PATTERN: &str = "_";
+
+const TEST_KEYWORD: &str = "test";
+
+const TRIPLE_QUOTE: &str = "\"\"\"";
+
+/// Decodes the escape sequences in the body of a non raw string literal.
+fn unescape(body: &str) -> Result<String, String> {
+    let mut string = String::with_capacity(body.len());
+    let mut chars = body.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('t') => string.push('\t'),
+            Some('r') => string.push('\r'),
+            Some('0') => string.push('\0'),
+            Some('"') => string.push('"'),
+            Some('\\') => string.push('\\'),
+            Some(other) => return Err(format!("Invalid escape sequence '\\{}'.", other)),
+            None => return Err("Invalid escape sequence at end of string.".to_owned()),
+        }
+    }
+
+    Ok(string)
+}
+
+// Byte offsets of the catch and finally operands within an encoded PushHandler.
+const CATCH_OPERAND: usize = 1;
+const FINALLY_OPERAND: usize = 1 + std::mem::size_of::<ByteCodeOffset>();
+
 impl<'a> StackSim<'a> {
     fn new(name: &'static str) -> Self {
         let mut locals = Vec::with_capacity(LOCALS_MAX_CAPACITY);
@@ -990,6 +2641,8 @@ pub struct Local<'a> {
     name: Token<'a>,
     depth: isize,
     captured: bool,
+    mutable: bool,
+    var_type: VarType<'a>,
 }
 
 impl<'a> Local<'a> {
@@ -998,6 +2651,8 @@ impl<'a> Local<'a> {
             name: token,
             depth,
             captured: false,
+            mutable: true,
+            var_type: VarType::default(),
         }
     }
 }
@@ -1005,6 +2660,8 @@ impl<'a> Local<'a> {
 pub struct ErrorHandler {
     pub panic_mode: bool,
     pub had_error: bool,
+    /// The path of the module being compiled, if it isn't the script itself.
+    pub module: Option<Gc<LoxStr>>,
 }
 
 impl ErrorHandler {
@@ -1021,7 +2678,10 @@ impl ErrorHandler {
             return;
         }
 
-        eprint!("[line {}] Error ", token.line);
+        match self.module {
+            Some(module) => eprint!("[line {}] ({}) Error ", token.line, module),
+            None => eprint!("[line {}] Error ", token.line),
+        }
 
         if token.kind == TokenType::Error {
             eprint!("while Scanning");
@@ -1055,10 +2715,17 @@ struct CompilerContext<'a> {
     function_type: FunctionType,
     stack_sim: StackSim<'a>,
     errh: ErrorHandler,
+    /// The annotated types of the named parameters, Any for the ones without annotations.
+    param_types: Vec<Type<'a>>,
+    return_type: Option<Type<'a>>,
+    /// The types of the values returned so far, joined into one.
+    returned_type: Option<Type<'a>>,
+    /// Whether the statement just compiled returns or throws on every path.
+    always_returns: bool,
 }
 
-impl CompilerContext<'_> {
-    fn new(function_type: FunctionType, name: Gc<LoxStr>) -> Self {
+impl<'a> CompilerContext<'a> {
+    fn new(function_type: FunctionType, name: Gc<LoxStr>, module: Option<Gc<LoxStr>>) -> Self {
         let this_name = if function_type == FunctionType::Method
             || function_type == FunctionType::Initializer
         {
@@ -1074,8 +2741,32 @@ impl CompilerContext<'_> {
             errh: ErrorHandler {
                 had_error: false,
                 panic_mode: false,
+                module,
             },
             upvalues: Vec::new(),
+            param_types: Vec::new(),
+            return_type: None,
+            returned_type: None,
+            always_returns: false,
+        }
+    }
+
+    /// The function's signature, with the return type inferred from its return statements
+    /// when it isn't annotated.
+    fn signature(&self) -> Signature<'a> {
+        let return_type = match &self.return_type {
+            Some(return_type) => return_type.clone(),
+            None if self.function.is_generator => Type::Any,
+            None => self.returned_type.clone().unwrap_or(Type::Nil),
+        };
+
+        Signature {
+            name: self.function.name.to_string(),
+            params: self.param_types.clone(),
+            arity: self.function.arity,
+            optional_arity: self.function.optional_arity,
+            has_rest: self.function.has_rest,
+            return_type,
         }
     }
 
@@ -1107,7 +2798,10 @@ impl CompilerContext<'_> {
 
 struct ClassContext<'a> {
     name: Token<'a>,
-    has_superclass: bool
+    has_superclass: bool,
+    is_trait: bool,
+    /// Whether the method being compiled is static, making 'this' the class itself.
+    in_static_method: bool,
 }
 
 impl<'a> ClassContext<'a> {
@@ -1115,6 +2809,8 @@ impl<'a> ClassContext<'a> {
         Self {
             name: token.clone(),
             has_superclass: false,
+            is_trait: false,
+            in_static_method: false,
         }
     }
 }
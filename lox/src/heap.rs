@@ -75,7 +75,7 @@ impl Heap {
         let grey_stack = grey_stack_borrow.as_mut();
 
         for call_frame in vm.call_frames.iter() {
-            call_frame.closure.mark_if_needed(grey_stack);
+            call_frame.mark_if_needed(grey_stack);
         }
 
         for upvalue in vm.open_upvalues.iter() {
@@ -86,12 +86,43 @@ impl Heap {
             value.mark_if_needed(grey_stack);
         }
 
+        // Each running generator or fiber holds the suspended state of whatever resumed it.
+        for coroutine in vm.running_coroutines.iter() {
+            coroutine.mark_if_needed(grey_stack);
+        }
+
         for (key, value) in vm.globals.iter() {
             key.mark_if_needed(grey_stack);
             value.mark_if_needed(grey_stack);
         }
 
+        for module in vm.modules.values() {
+            module.mark_if_needed(grey_stack);
+        }
+
         vm.class_init_method.mark_if_needed(grey_stack);
+        vm.operator_methods.mark_if_needed(grey_stack);
+        vm.iterator_method.mark_if_needed(grey_stack);
+        vm.next_method.mark_if_needed(grey_stack);
+        vm.variant_name_property.mark_if_needed(grey_stack);
+        vm.variant_ordinal_property.mark_if_needed(grey_stack);
+        vm.enum_variants_property.mark_if_needed(grey_stack);
+        vm.error_message_field.mark_if_needed(grey_stack);
+
+        if let Some(tests) = &vm.tests {
+            for test in tests.iter() {
+                test.mark_if_needed(grey_stack);
+            }
+        }
+        vm.error_trace_field.mark_if_needed(grey_stack);
+
+        if let Some(error_class) = vm.error_class {
+            error_class.mark_if_needed(grey_stack);
+        }
+
+        if let Some(fiber_class) = vm.fiber_class {
+            fiber_class.mark_if_needed(grey_stack);
+        }
     }
 
     fn mark_heap(&self, vm: &Vm) {
@@ -203,15 +234,19 @@ impl Heap {
 
     // Some allocated objects may grow in size in response to certain actions. For example setting a field
     // will grow the hashmap used. Any action performed here should keep in mind that call this function may trigger the GC.
-    pub fn update_allocation<T: Trace>(&self, obj: Gc<T>, mut action: impl FnMut(), vm: &Vm) {
+    pub fn update_allocation<T: Trace>(&self, obj: Gc<T>, action: impl FnMut(), vm: &Vm) {
+        self.resize_allocation(obj, action);
+        self.collect_if_needed(vm);
+    }
+
+    /// Like update_allocation but never triggers the GC, for natives which can't root their values.
+    pub fn resize_allocation<T: Trace>(&self, obj: Gc<T>, mut action: impl FnMut()) {
         let curr_size = obj.bytes_allocated();
         action();
         let new_size = obj.bytes_allocated();
 
         let new_bytes_allocated = self.bytes_allocated.get() + new_size - curr_size;
         self.bytes_allocated.replace(new_bytes_allocated);
-
-        self.collect_if_needed(vm);
     }
 }
 
@@ -266,11 +301,26 @@ where
     }
 }
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+#[derive(Debug)]
 pub struct Gc<T: 'static + Trace> {
     ptr: *mut Obj<T>,
 }
 
+// Gc pointers compare and hash by identity, which is also what makes interned strings cheap keys.
+impl<T: Trace> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T: Trace> Eq for Gc<T> {}
+
+impl<T: Trace> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.hash(state);
+    }
+}
+
 impl<T: Trace> Copy for Gc<T> {}
 impl<T: Trace> Clone for Gc<T> {
     fn clone(&self) -> Self {
@@ -1,4 +1,4 @@
-use std::mem;
+use std::{mem, path::{Path, PathBuf}};
 
 use crate::{compiler::Compiler, heap::{Gc, Heap}, object::LoxFun, opcodes::Chunk, scanner::{Scanner, TokenType as T}, vm::Vm};
 
@@ -15,8 +15,9 @@ impl Interpreter {
         Interpreter {}
     }
 
-    pub fn interpret(&mut self, source: &str) -> InterpreterResult {
-        let compile_res = self.compile(source);
+    /// Runs `source` as the main module. Imports are resolved relative to `path`.
+    pub fn interpret(&mut self, source: &str, path: &Path) -> InterpreterResult {
+        let compile_res = self.compile(source, path);
         if let Some(vm_init) = compile_res {
             return self.run(vm_init);
         } else {
@@ -24,13 +25,53 @@ impl Interpreter {
         }
     }
 
-    fn compile(&mut self, source: &str) -> Option<VmInit> {
-        let mut compiler = Compiler::new(source);
-        let compiler_res = compiler.compile();
-        let heap = compiler.heap;
+    /// Runs `source` and then the test blocks it defined.
+    pub fn test(&mut self, source: &str, path: &Path) -> InterpreterResult {
+        let vm_init = match self.compile(source, path) {
+            Some(vm_init) => vm_init,
+            None => return InterpreterResult::CompileError,
+        };
+
+        let mut vm = Vm::new(vm_init);
+        vm.tests = Some(Vec::new());
+
+        match vm.run() {
+            InterpreterResult::Ok if vm.run_tests() => InterpreterResult::Ok,
+            InterpreterResult::Ok => InterpreterResult::RuntimeError,
+            result => result,
+        }
+    }
+
+    /// Compiles `source` without running it, checking its types as well when `check_types` is set.
+    pub fn check(&mut self, source: &str, check_types: bool) -> InterpreterResult {
+        let heap = Heap::new();
+        let mut compiler = Compiler::new(source, &heap);
+        if check_types {
+            compiler = compiler.with_type_checking();
+        }
+
+        if compiler.compile().is_none() {
+            return InterpreterResult::CompileError;
+        }
+
+        let diagnostics = compiler.type_diagnostics();
+        for diagnostic in diagnostics.iter() {
+            eprintln!("{}", diagnostic);
+        }
+
+        if diagnostics.is_empty() {
+            InterpreterResult::Ok
+        } else {
+            InterpreterResult::CompileError
+        }
+    }
+
+    fn compile(&mut self, source: &str, path: &Path) -> Option<VmInit> {
+        let heap = Heap::new();
+        let compiler_res = Compiler::new(source, &heap).compile();
 
         if let Some(lox_fun) = compiler_res {
-            Some(VmInit {heap, function: lox_fun})
+            Some(VmInit {heap, function: lox_fun, path: path.to_path_buf()})
         } else {
             None
         }
@@ -66,4 +107,5 @@ impl Interpreter {
 pub struct VmInit {
     pub function: Gc<LoxFun>,
     pub heap: Heap,
+    pub path: PathBuf,
 }
@@ -8,3 +8,5 @@ mod precedence;
 mod heap;
 mod object;
 mod native;
+mod bigint;
+mod types;
@@ -1,10 +1,27 @@
-use std::env;
+use std::{env, process};
 
-use lox::repl::run_file;
+use lox::repl::{check_file, run_file, test_file};
 
 fn main() {
     let args: Vec<_> = env::args().collect();
 
+    if args.len() == 3 && args[1] == "test" {
+        test_file(&args[2]);
+        return;
+    }
+
+    if args.len() >= 2 && args[1] == "check" {
+        match &args[2..] {
+            [file_path] if file_path != "--types" => check_file(file_path, false),
+            [flag, file_path] if flag == "--types" => check_file(file_path, true),
+            _ => {
+                eprintln!("Usage: lox check [--types] <file>");
+                process::exit(64);
+            }
+        }
+        return;
+    }
+
     #[cfg(feature = "repl")]
     if args.len() == 1 {
         lox::repl::repl();
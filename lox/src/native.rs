@@ -2,14 +2,20 @@ use std::{fmt::{self, Formatter}, mem, time::Instant, write};
 
 use fmt::Display;
 
-use crate::{heap::{Heap, Trace}, opcodes::{ArgCount, Value}};
+use crate::{
+    bigint::BigInt,
+    heap::{Gc, Heap, LoxStr, Trace},
+    object::{Fields, LoxBoundMethod, LoxInstance, LoxList},
+    opcodes::{ArgCount, Number, Value},
+};
 
 // pub fn clock_native(arg_count: ArgCount, args: &[Value]) -> Value {
 //     Value::Number(program_start.elapsed().as_secs_f64())
 // }
 
 pub trait NativeFun: fmt::Debug + 'static {
-    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Value;
+    /// Returns the call's result, or the message of an error for the vm to throw.
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Result<Value, String>;
 }
 
 #[derive(Debug)]
@@ -54,8 +60,8 @@ impl ClockNative {
 }
 
 impl NativeFun for ClockNative {
-    fn call(&mut self, _arg_count: ArgCount, _args: &[Value], _heap: &Heap) -> Value {
-        Value::Number(self.start.elapsed().as_secs_f64())
+    fn call(&mut self, _arg_count: ArgCount, _args: &[Value], _heap: &Heap) -> Result<Value, String> {
+        Ok(Value::Number(self.start.elapsed().as_secs_f64()))
     }
 }
 
@@ -69,13 +75,315 @@ impl ValueToStrConverter {
 }
 
 impl NativeFun for ValueToStrConverter {
-    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Value {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Result<Value, String> {
         if arg_count < 1 {
             let str_ref = heap.intern_string("");
-            Value::String(str_ref)
+            Ok(Value::String(str_ref))
         } else {
             let str_ref = heap.intern_string(args[0].to_string());
-            Value::String(str_ref)
+            Ok(Value::String(str_ref))
         }
     }
-}
\ No newline at end of file
+}
+
+/// Converts a number or numeric string to an int, truncating floats towards zero.
+/// Values too large for an int give a BigInt.
+#[derive(Debug, Clone, Copy)]
+pub struct IntConverter {}
+
+impl IntConverter {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for IntConverter {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Result<Value, String> {
+        if arg_count != 1 {
+            return Err(format!("Expected 1 arguments but got {}.", arg_count));
+        }
+
+        let converted = match args[0] {
+            Value::Int(_) | Value::BigInt(_) => return Ok(args[0]),
+            Value::Number(value) => BigInt::from_float(value),
+            Value::String(string) => BigInt::parse(string.as_str().trim(), 10),
+            _ => None,
+        };
+
+        converted
+            .map(|value| value.into_value(heap))
+            .ok_or_else(|| format!("Can't convert '{}' to an int.", args[0]))
+    }
+}
+
+/// Converts a number or numeric string to a float.
+#[derive(Debug, Clone, Copy)]
+pub struct FloatConverter {}
+
+impl FloatConverter {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for FloatConverter {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], _heap: &Heap) -> Result<Value, String> {
+        if arg_count != 1 {
+            return Err(format!("Expected 1 arguments but got {}.", arg_count));
+        }
+
+        let converted = match args[0] {
+            Value::Int(value) => Some(value as Number),
+            Value::BigInt(value) => Some(value.to_float()),
+            Value::Number(value) => Some(value),
+            Value::String(string) => string.as_str().trim().parse::<Number>().ok(),
+            _ => None,
+        };
+
+        converted
+            .map(Value::Number)
+            .ok_or_else(|| format!("Can't convert '{}' to a float.", args[0]))
+    }
+}
+
+fn check_arity(expected: ArgCount, arg_count: ArgCount) -> Result<(), String> {
+    if arg_count != expected {
+        return Err(format!("Expected {} arguments but got {}.", expected, arg_count));
+    }
+
+    Ok(())
+}
+
+fn instance_arg(value: Value, native_name: &str) -> Result<Gc<LoxInstance>, String> {
+    match value {
+        Value::Instance(instance) => Ok(instance),
+        _ => Err(format!("{}() expects an instance.", native_name)),
+    }
+}
+
+/// Like `instance_arg`, but rejects enum variants, whose fields can't change.
+fn mutable_instance_arg(value: Value, native_name: &str) -> Result<Gc<LoxInstance>, String> {
+    let instance = instance_arg(value, native_name)?;
+    match instance.class.superclass {
+        Some(enum_class) if instance.class.variant.is_some() => Err(format!(
+            "{}() can't change enum variant {}.{}.",
+            native_name,
+            enum_class.name(),
+            instance.class.name()
+        )),
+        _ => Ok(instance),
+    }
+}
+
+fn name_arg(value: Value) -> Result<Gc<LoxStr>, String> {
+    match value {
+        Value::String(name) => Ok(name),
+        _ => Err("Property name must be a string.".to_string()),
+    }
+}
+
+/// Makes a list of a table's names, sorted so the order doesn't depend on the hashing.
+fn names_list(table: &Fields, heap: &Heap) -> Value {
+    let mut names: Vec<_> = table.keys().copied().collect();
+    names.sort_by(|lhs, rhs| lhs.as_str().cmp(rhs.as_str()));
+
+    let mut list = LoxList::new();
+    list.items = names.into_iter().map(Value::String).collect();
+    Value::List(heap.manage(list))
+}
+
+/// Lists the names of an instance's fields.
+#[derive(Debug, Clone, Copy)]
+pub struct FieldsNative {}
+
+impl FieldsNative {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for FieldsNative {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Result<Value, String> {
+        check_arity(1, arg_count)?;
+        let instance = instance_arg(args[0], "fields")?;
+
+        Ok(names_list(&instance.fields, heap))
+    }
+}
+
+/// Lists the names of the methods a class's instances have, including inherited ones.
+#[derive(Debug, Clone, Copy)]
+pub struct MethodsNative {}
+
+impl MethodsNative {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for MethodsNative {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Result<Value, String> {
+        check_arity(1, arg_count)?;
+
+        match args[0] {
+            Value::Class(class) => Ok(names_list(&class.methods, heap)),
+            _ => Err("methods() expects a class.".to_string()),
+        }
+    }
+}
+
+/// Reads a field or method whose name is only known at runtime. Methods come back bound
+/// to the instance, but getters aren't run since natives can't call back into Lox code.
+#[derive(Debug, Clone, Copy)]
+pub struct GetFieldNative {}
+
+impl GetFieldNative {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for GetFieldNative {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Result<Value, String> {
+        check_arity(2, arg_count)?;
+        let instance = instance_arg(args[0], "getField")?;
+        let name = name_arg(args[1])?;
+
+        if let Some(value) = instance.fields.get(&name) {
+            return Ok(*value);
+        }
+
+        match instance.class.methods.get(&name) {
+            Some(method) => {
+                let bound_method = LoxBoundMethod::new(method.unwrap_closure(), args[0]);
+                Ok(Value::BoundMethod(heap.manage(bound_method)))
+            }
+            None => Err(format!("Undefined property '{}'.", name)),
+        }
+    }
+}
+
+/// Sets a field whose name is only known at runtime, returning the value like assignment does.
+#[derive(Debug, Clone, Copy)]
+pub struct SetFieldNative {}
+
+impl SetFieldNative {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for SetFieldNative {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Result<Value, String> {
+        check_arity(3, arg_count)?;
+        let mut instance = mutable_instance_arg(args[0], "setField")?;
+        let name = name_arg(args[1])?;
+        let value = args[2];
+
+        heap.resize_allocation(instance, || {
+            instance.fields.insert(name, value);
+        });
+
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HasFieldNative {}
+
+impl HasFieldNative {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for HasFieldNative {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], _heap: &Heap) -> Result<Value, String> {
+        check_arity(2, arg_count)?;
+        let instance = instance_arg(args[0], "hasField")?;
+        let name = name_arg(args[1])?;
+
+        Ok(Value::Boolean(instance.fields.contains_key(&name)))
+    }
+}
+
+/// Removes a field, returning whether the instance had it.
+#[derive(Debug, Clone, Copy)]
+pub struct DeleteFieldNative {}
+
+impl DeleteFieldNative {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for DeleteFieldNative {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], heap: &Heap) -> Result<Value, String> {
+        check_arity(2, arg_count)?;
+        let mut instance = mutable_instance_arg(args[0], "deleteField")?;
+        let name = name_arg(args[1])?;
+
+        let mut removed = false;
+        heap.resize_allocation(instance, || {
+            removed = instance.fields.remove(&name).is_some();
+        });
+
+        Ok(Value::Boolean(removed))
+    }
+}
+
+/// Checks whether a method can be invoked on a value. Instances respond to their class's
+/// methods and to fields holding something callable, classes respond to their static methods.
+#[derive(Debug, Clone, Copy)]
+pub struct RespondsToNative {}
+
+impl RespondsToNative {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for RespondsToNative {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], _heap: &Heap) -> Result<Value, String> {
+        check_arity(2, arg_count)?;
+        let name = name_arg(args[1])?;
+
+        let responds = match args[0] {
+            Value::Instance(instance) => {
+                instance.class.methods.contains_key(&name)
+                    || matches!(
+                        instance.fields.get(&name),
+                        Some(Value::Closure(_))
+                            | Some(Value::NativeFunction(_))
+                            | Some(Value::BoundMethod(_))
+                            | Some(Value::Class(_))
+                    )
+            }
+            Value::Class(class) => class.static_methods.contains_key(&name),
+            _ => false,
+        };
+
+        Ok(Value::Boolean(responds))
+    }
+}
+
+/// Returns an instance's class, or nil for values that aren't instances.
+#[derive(Debug, Clone, Copy)]
+pub struct ClassOfNative {}
+
+impl ClassOfNative {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl NativeFun for ClassOfNative {
+    fn call(&mut self, arg_count: ArgCount, args: &[Value], _heap: &Heap) -> Result<Value, String> {
+        check_arity(1, arg_count)?;
+
+        match args[0] {
+            Value::Instance(instance) => Ok(Value::Class(instance.class)),
+            _ => Ok(Value::Nil),
+        }
+    }
+}
@@ -1,8 +1,9 @@
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{self, Display, Formatter},
     mem,
+    path::{Path, PathBuf},
     ptr::NonNull,
     write,
 };
@@ -11,7 +12,7 @@ use crate::{
     heap::{Gc, LoxStr, Trace},
     native::LoxNativeFun,
     opcodes::{Chunk, Value},
-    vm::StackIndex,
+    vm::{ExecutionContext, StackIndex},
 };
 
 pub type Arity = i32;
@@ -20,7 +21,14 @@ pub type Arity = i32;
 pub struct LoxFun {
     pub chunk: Chunk,
     pub name: Gc<LoxStr>,
+    /// Number of parameters without a default value.
     pub arity: Arity,
+    /// Number of parameters with a default value, which follow the required ones.
+    pub optional_arity: Arity,
+    /// Whether extra arguments are collected into a list in a final parameter.
+    pub has_rest: bool,
+    /// Whether calling the function creates a generator instead of running its body.
+    pub is_generator: bool,
     pub upvalues: Box<[UpvalueSim]>,
 }
 
@@ -30,9 +38,41 @@ impl LoxFun {
             chunk: Chunk::new(),
             name,
             arity: 0,
+            optional_arity: 0,
+            has_rest: false,
+            is_generator: false,
             upvalues: Box::new([]),
         }
     }
+
+    /// The most arguments that bind to named parameters.
+    pub fn max_arity(&self) -> Arity {
+        self.arity + self.optional_arity
+    }
+
+    pub fn accepts(&self, arg_count: Arity) -> bool {
+        arg_count >= self.arity && (self.has_rest || arg_count <= self.max_arity())
+    }
+
+    pub fn arity_error(&self, arg_count: Arity) -> String {
+        arity_error(self.arity, self.optional_arity, self.has_rest, arg_count)
+    }
+}
+
+/// Describes a call with `arg_count` arguments to a function with the given parameters.
+pub fn arity_error(arity: Arity, optional_arity: Arity, has_rest: bool, arg_count: Arity) -> String {
+    if has_rest {
+        format!("Expected at least {} arguments but got {}.", arity, arg_count)
+    } else if optional_arity > 0 {
+        format!(
+            "Expected {} to {} arguments but got {}.",
+            arity,
+            arity + optional_arity,
+            arg_count
+        )
+    } else {
+        format!("Expected {} arguments but got {}.", arity, arg_count)
+    }
 }
 
 impl Default for LoxFun {
@@ -41,6 +81,9 @@ impl Default for LoxFun {
             chunk: Chunk::new(),
             name: Gc::dangling(),
             arity: 0,
+            optional_arity: 0,
+            has_rest: false,
+            is_generator: false,
             upvalues: Box::new([]),
         }
     }
@@ -72,6 +115,7 @@ pub enum FunctionType {
     Method,
     Initializer,
     Script,
+    Module,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -84,15 +128,19 @@ pub enum UpvalueSim {
 pub struct Upvalue {
     pub location: NonNull<Value>,
     value: Value,
+    /// The generator or fiber whose stack an open upvalue points into. It's kept
+    /// alive until the upvalue closes, even if the coroutine itself is unreachable.
+    owner: Option<Gc<LoxCoroutine>>,
 }
 
 impl Upvalue {
-    pub fn new(ptr: *mut Value) -> Self {
+    pub fn new(ptr: *mut Value, owner: Option<Gc<LoxCoroutine>>) -> Self {
         let location = unsafe { NonNull::new_unchecked(ptr) };
 
         Self {
             location,
             value: Value::Nil,
+            owner,
         }
     }
 
@@ -101,6 +149,7 @@ impl Upvalue {
             self.value = *self.location.as_ref();
             self.location = NonNull::new_unchecked(&mut self.value as *mut _);
         }
+        self.owner = None;
     }
 
     pub fn value_ptr(&self) -> *mut Value {
@@ -111,6 +160,9 @@ impl Upvalue {
 impl Trace for Upvalue {
     fn trace(&self, grey_stack: &mut crate::heap::GreyStack) {
         self.as_ref().mark_if_needed(grey_stack);
+        if let Some(owner) = self.owner {
+            owner.mark_if_needed(grey_stack);
+        }
     }
 
     fn bytes_allocated(&self) -> usize {
@@ -134,13 +186,16 @@ impl AsMut<Value> for Upvalue {
 pub struct LoxClosure {
     pub function: Gc<LoxFun>,
     pub upvalues: Box<[Gc<Upvalue>]>,
+    /// The module whose globals this closure reads and writes.
+    pub module: Gc<LoxModule>,
 }
 
 impl LoxClosure {
-    pub fn new(function: Gc<LoxFun>) -> Self {
+    pub fn new(function: Gc<LoxFun>, module: Gc<LoxModule>) -> Self {
         Self {
             function,
             upvalues: Box::new([]),
+            module,
         }
     }
 }
@@ -148,6 +203,7 @@ impl LoxClosure {
 impl Trace for LoxClosure {
     fn trace(&self, grey_stack: &mut crate::heap::GreyStack) {
         self.function.mark_if_needed(grey_stack);
+        self.module.mark_if_needed(grey_stack);
         for upvalue in self.upvalues.iter() {
             upvalue.mark_if_needed(grey_stack);
 
@@ -166,12 +222,73 @@ impl Trace for LoxClosure {
 #[derive(Debug)]
 pub struct LoxClass {
     name: Gc<LoxStr>,
-    pub methods: Fields
+    pub methods: Fields,
+    /// Methods declared with a `class` prefix, called on the class itself.
+    pub static_methods: Fields,
+    /// Methods declared without a parameter list, run when the property is read.
+    pub getters: Fields,
+    pub superclass: Option<Gc<LoxClass>>,
+    /// An enum's variants in declaration order. Variants without fields are a single
+    /// instance, the others are classes that construct instances.
+    pub variants: Vec<(Gc<LoxStr>, Value)>,
+    /// Set on the subclass created for each of an enum's variants.
+    pub variant: Option<EnumVariant>,
+    /// Whether this was declared with `enum`, even if it has no variants.
+    pub is_enum: bool,
+    /// Whether this was declared with `trait`. Traits can't be instantiated or inherited from.
+    pub is_trait: bool,
+    /// The traits listed after `with`, so their methods' receivers match them as patterns.
+    pub traits: Vec<Gc<LoxClass>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EnumVariant {
+    pub ordinal: usize,
+    /// The names of the values a variant carries, in declaration order.
+    pub fields: Vec<Gc<LoxStr>>,
 }
 
 impl LoxClass {
     pub fn new(name: Gc<LoxStr>) -> Self {
-        Self { name , methods: HashMap::new()}
+        Self {
+            name,
+            methods: HashMap::new(),
+            static_methods: HashMap::new(),
+            getters: HashMap::new(),
+            superclass: None,
+            variants: Vec::new(),
+            variant: None,
+            is_enum: false,
+            is_trait: false,
+            traits: Vec::new(),
+        }
+    }
+
+    pub fn variant(&self, name: Gc<LoxStr>) -> Option<Value> {
+        self.variants
+            .iter()
+            .find(|(variant_name, _)| *variant_name == name)
+            .map(|(_, variant)| *variant)
+    }
+
+    pub fn name(&self) -> Gc<LoxStr> {
+        self.name
+    }
+
+    /// Walks the superclass chain to check whether this class is, derives from or uses `class`.
+    pub fn is_subclass_of(&self, class: Gc<LoxClass>) -> bool {
+        if self as *const LoxClass == class.get_ref() as *const LoxClass {
+            return true;
+        }
+
+        if self.traits.iter().any(|t| t.is_subclass_of(class)) {
+            return true;
+        }
+
+        match self.superclass {
+            Some(superclass) => superclass.is_subclass_of(class),
+            None => false,
+        }
     }
 }
 
@@ -179,17 +296,45 @@ impl Trace for LoxClass {
     fn trace(&self, grey_stack: &mut crate::heap::GreyStack) {
         self.name.mark_if_needed(grey_stack);
 
-        for (k, v) in self.methods.iter() {
-            k.mark_if_needed(grey_stack);
-            v.mark_if_needed(grey_stack);
+        if let Some(superclass) = self.superclass {
+            superclass.mark_if_needed(grey_stack);
+        }
+
+        for table in [&self.methods, &self.static_methods, &self.getters].iter() {
+            for (k, v) in table.iter() {
+                k.mark_if_needed(grey_stack);
+                v.mark_if_needed(grey_stack);
+            }
+        }
+
+        for (name, variant) in self.variants.iter() {
+            name.mark_if_needed(grey_stack);
+            variant.mark_if_needed(grey_stack);
+        }
+
+        if let Some(variant) = &self.variant {
+            for field in variant.fields.iter() {
+                field.mark_if_needed(grey_stack);
+            }
+        }
+
+        for used_trait in self.traits.iter() {
+            used_trait.mark_if_needed(grey_stack);
         }
     }
 
     fn bytes_allocated(&self) -> usize {
+        let entries = self.methods.capacity() + self.static_methods.capacity() + self.getters.capacity()
+            + self.variants.capacity();
         let methods_heap_size =
-            self.methods.capacity() * (mem::size_of::<Value>() + mem::size_of::<Gc<LoxStr>>());
-
-        methods_heap_size + mem::size_of::<Self>()
+            entries * (mem::size_of::<Value>() + mem::size_of::<Gc<LoxStr>>());
+        let fields_size = self
+            .variant
+            .as_ref()
+            .map_or(0, |variant| variant.fields.capacity() * mem::size_of::<Gc<LoxStr>>());
+        let traits_size = self.traits.capacity() * mem::size_of::<Gc<LoxClass>>();
+
+        methods_heap_size + fields_size + traits_size + mem::size_of::<Self>()
     }
 }
 
@@ -208,6 +353,23 @@ impl LoxInstance {
             fields: HashMap::new(),
         }
     }
+
+    /// Writes an enum variant as `Enum.Variant`, followed by the values it carries.
+    pub fn fmt_variant(&self, variant: &EnumVariant, f: &mut Formatter<'_>) -> fmt::Result {
+        let enum_name = self.class.superclass.unwrap().name();
+        write!(f, "{}.{}", enum_name, self.class.name())?;
+
+        if !variant.fields.is_empty() {
+            let values: Vec<_> = variant
+                .fields
+                .iter()
+                .map(|field| self.fields.get(field).copied().unwrap_or(Value::Nil).to_string())
+                .collect();
+            write!(f, "({})", values.join(", "))?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Trace for LoxInstance {
@@ -228,6 +390,108 @@ impl Trace for LoxInstance {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct LoxList {
+    pub items: Vec<Value>,
+}
+
+impl LoxList {
+    pub fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+}
+
+impl Display for LoxList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", item)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl Trace for LoxList {
+    fn trace(&self, grey_stack: &mut crate::heap::GreyStack) {
+        for item in self.items.iter() {
+            item.mark_if_needed(grey_stack);
+        }
+    }
+
+    fn bytes_allocated(&self) -> usize {
+        mem::size_of::<Self>() + self.items.capacity() * mem::size_of::<Value>()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineKind {
+    Generator,
+    Fiber,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineState {
+    /// Created but not resumed yet.
+    Created,
+    Suspended,
+    Running,
+    Done,
+}
+
+/// A generator or fiber, either of which runs on its own stack.
+pub struct LoxCoroutine {
+    pub kind: CoroutineKind,
+    pub closure: Gc<LoxClosure>,
+    /// The coroutine's own stack and frames while it's suspended. While it runs the vm
+    /// keeps its resumer's here instead.
+    pub context: ExecutionContext,
+    pub state: CoroutineState,
+}
+
+impl LoxCoroutine {
+    pub fn new(kind: CoroutineKind, closure: Gc<LoxClosure>, context: ExecutionContext) -> Self {
+        Self {
+            kind,
+            closure,
+            context,
+            state: CoroutineState::Created,
+        }
+    }
+}
+
+impl fmt::Debug for LoxCoroutine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoxCoroutine")
+            .field("kind", &self.kind)
+            .field("closure", &self.closure)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl Display for LoxCoroutine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            CoroutineKind::Generator => write!(f, "<generator {}>", self.closure.function.name),
+            CoroutineKind::Fiber => write!(f, "<fiber {}>", self.closure.function.name),
+        }
+    }
+}
+
+impl Trace for LoxCoroutine {
+    fn trace(&self, grey_stack: &mut crate::heap::GreyStack) {
+        self.closure.mark_if_needed(grey_stack);
+        self.context.mark_if_needed(grey_stack);
+    }
+
+    fn bytes_allocated(&self) -> usize {
+        mem::size_of::<Self>() + self.context.bytes_allocated()
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct LoxBoundMethod {
     pub method: Gc<LoxClosure>,
@@ -253,4 +517,59 @@ impl Trace for LoxBoundMethod {
     fn bytes_allocated(&self) -> usize {
         mem::size_of::<Self>()
     }
-}
\ No newline at end of file
+}
+/// A lox source file and the global namespace its top level code defines.
+#[derive(Debug)]
+pub struct LoxModule {
+    pub name: Gc<LoxStr>,
+    pub path: PathBuf,
+    pub globals: Fields,
+    /// Names of the globals declared with 'const'.
+    pub constants: HashSet<Gc<LoxStr>>,
+    /// Set once the module's top level code has finished running.
+    pub loaded: bool,
+}
+
+impl LoxModule {
+    pub fn new(name: Gc<LoxStr>, path: PathBuf) -> Self {
+        Self {
+            name,
+            path,
+            globals: HashMap::new(),
+            constants: HashSet::new(),
+            loaded: false,
+        }
+    }
+
+    /// The directory that imports from this module are resolved against.
+    pub fn directory(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new(""))
+    }
+}
+
+impl Display for LoxModule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "<module {}>", self.name)
+    }
+}
+
+impl Trace for LoxModule {
+    fn trace(&self, grey_stack: &mut crate::heap::GreyStack) {
+        self.name.mark_if_needed(grey_stack);
+        for (k, v) in self.globals.iter() {
+            k.mark_if_needed(grey_stack);
+            v.mark_if_needed(grey_stack);
+        }
+        for k in self.constants.iter() {
+            k.mark_if_needed(grey_stack);
+        }
+    }
+
+    fn bytes_allocated(&self) -> usize {
+        let self_size = mem::size_of::<Self>();
+        let globals_heap_size =
+            self.globals.capacity() * (mem::size_of::<Value>() + mem::size_of::<Gc<LoxStr>>());
+
+        self_size + globals_heap_size
+    }
+}
@@ -1,9 +1,11 @@
 use fmt::{Display, Formatter, Debug};
 use std::{convert::TryFrom, error::Error, fmt};
+use std::hash::{Hash, Hasher};
 use std::mem;
 
 
 pub type Number = f64;
+pub type Int = i64;
 pub type ConstantIndex = u8;
 pub type ByteCodeOffset = u16;
 pub type ArgCount = u8;
@@ -15,7 +17,7 @@ trait ByteCodeEncodeDecode: Sized {
 }
 use lox_macros::ByteCodeEncodeDecode;
 
-use crate::{heap::{Gc, GreyStack, LoxStr}, native::LoxNativeFun, object::{LoxBoundMethod, LoxClass, LoxClosure, LoxFun, LoxInstance}};
+use crate::{bigint::BigInt, heap::{Gc, GreyStack, LoxStr}, native::LoxNativeFun, object::{LoxBoundMethod, LoxClass, LoxClosure, LoxFun, LoxCoroutine, LoxInstance, LoxList, LoxModule}, vm::StackIndex};
 
 #[derive(Debug, Clone, Copy, ByteCodeEncodeDecode)]
 pub enum Instruction {
@@ -29,6 +31,8 @@ pub enum Instruction {
     Multiply,
     Divide,
     Equal,
+    // Identity comparison, which unlike Equal never calls __eq__.
+    Is,
     Greater,
     Less,
 
@@ -41,8 +45,13 @@ pub enum Instruction {
     Print,
 
     Pop,
+    // Stack shuffles used when a value is needed more than once.
+    Dup,
+    Swap,
+    Over,
 
     DefineGlobal(ConstantIndex),
+    DefineConstGlobal(ConstantIndex),
     GetGlobal(ConstantIndex),
     SetGlobal(ConstantIndex),
 
@@ -52,6 +61,10 @@ pub enum Instruction {
     JumpFwdIfFalse(ByteCodeOffset),
     JumpForward(ByteCodeOffset),
     JumpBack(ByteCodeOffset),
+    // Jumps when the value on top of the stack is nil, leaving it there either way.
+    JumpIfNil(ByteCodeOffset),
+    // Skips a parameter's default value when the caller passed an argument for it.
+    JumpIfArgPassed(ByteCodeOffset, ArgCount),
 
     Call(ArgCount),
     Closure(ConstantIndex),
@@ -66,11 +79,63 @@ pub enum Instruction {
     SetProperty(ConstantIndex),
 
     Method(ConstantIndex),
+    StaticMethod(ConstantIndex),
+    Getter(ConstantIndex),
     Invoke(ConstantIndex, ArgCount),
 
     Inherit,
+    // Pops a test block's closure, which the vm keeps when it's running tests.
+    Test,
+    // Throws the error for a failed assertion, quoting its condition's source. The
+    // assertion's message, or nil, is on top of the stack.
+    AssertFailed(ConstantIndex),
+    // Creates a trait, which is a class that can only be used after 'with'.
+    Trait(ConstantIndex),
+    // Pops a class and a trait, copying the trait's methods into the class.
+    UseTrait,
+    // Pops a class and a value, pushing whether the value is an instance of the class.
+    IsInstance,
+    // Pops an enum variant and a value, pushing whether the value is an instance of the
+    // variant. The operand is how many fields the pattern binds, which must match the variant.
+    IsVariant(ArgCount),
+    // Reads the field of an enum variant instance at the given position.
+    GetVariantField(ArgCount),
     GetSuper(ConstantIndex),
-    SuperInvoke(ConstantIndex, ArgCount)
+    SuperInvoke(ConstantIndex, ArgCount),
+
+    // Exception handling. PushHandler offsets are relative to the instruction
+    // and an offset of 0 marks an absent catch or finally block.
+    Throw,
+    PushHandler(ByteCodeOffset, ByteCodeOffset),
+    PopHandler,
+    EnterFinally,
+    EndFinally,
+
+    ImportModule(ConstantIndex),
+
+    // Suspends the running generator, handing the popped value to its resumer.
+    Yield,
+
+    // Creates an enum, which is a class whose instances are its variants.
+    Enum(ConstantIndex),
+    // Adds a variant to the enum below the variant's field names.
+    EnumVariant(ConstantIndex, ArgCount),
+
+    BuildList(ArgCount),
+    // Replaces a list with its first N items, using nil for missing ones.
+    UnpackList(ArgCount),
+    // Like UnpackList, followed by a list of the items after the first N.
+    UnpackListRest(ArgCount),
+    // Replaces an instance and the N field names above it with those fields' values, using nil for missing ones.
+    UnpackFields(ArgCount),
+    GetIndex,
+    SetIndex,
+
+    // For-in loops. The iterator and its position are kept in two adjacent locals,
+    // with the operands naming the first one's stack slot.
+    IterInit,
+    IterNext(StackIndex),
+    JumpIfIterDone(ByteCodeOffset, StackIndex),
 }
 
 impl Instruction {
@@ -81,6 +146,22 @@ impl Instruction {
     pub fn jump_placeholder() -> Self {
         Instruction::JumpForward(!0)
     }
+
+    pub fn jump_if_nil_placeholder() -> Self {
+        Instruction::JumpIfNil(!0)
+    }
+
+    pub fn jump_if_arg_passed_placeholder(param_index: ArgCount) -> Self {
+        Instruction::JumpIfArgPassed(!0, param_index)
+    }
+
+    pub fn jump_if_iter_done_placeholder(iterator: StackIndex) -> Self {
+        Instruction::JumpIfIterDone(!0, iterator)
+    }
+
+    pub fn push_handler_placeholder() -> Self {
+        Instruction::PushHandler(0, 0)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -93,7 +174,12 @@ pub struct Chunk {
 #[derive(Clone, Debug, Copy)]
 pub enum Value {
     Nil,
+    /// A float. These always print with a decimal point, so `1.0` prints as `1.0`.
     Number(Number),
+    /// A 64 bit integer, which prints without a decimal point, so `1` prints as `1`.
+    Int(Int),
+    /// An integer too large for an int. Smaller values are always stored as ints.
+    BigInt(Gc<BigInt>),
     Boolean(bool),
     String(Gc<LoxStr>),
     Function(Gc<LoxFun>),
@@ -101,7 +187,11 @@ pub enum Value {
     Closure(Gc<LoxClosure>),
     Class(Gc<LoxClass>),
     Instance(Gc<LoxInstance>),
-    BoundMethod(Gc<LoxBoundMethod>)
+    BoundMethod(Gc<LoxBoundMethod>),
+    Module(Gc<LoxModule>),
+    List(Gc<LoxList>),
+    Generator(Gc<LoxCoroutine>),
+    Fiber(Gc<LoxCoroutine>),
 }
 
 impl Value {
@@ -114,6 +204,11 @@ impl Value {
             Value::Class(class) => class.mark_if_needed(grey_stack),
             Value::Instance(instance) => instance.mark_if_needed(grey_stack),
             Value::BoundMethod(obj_ref) => obj_ref.mark_if_needed(grey_stack),
+            Value::Module(module) => module.mark_if_needed(grey_stack),
+            Value::List(list) => list.mark_if_needed(grey_stack),
+            Value::Generator(generator) => generator.mark_if_needed(grey_stack),
+            Value::Fiber(fiber) => fiber.mark_if_needed(grey_stack),
+            Value::BigInt(big_int) => big_int.mark_if_needed(grey_stack),
             _ => {}
         }
     }
@@ -159,6 +254,12 @@ impl From<Number> for Value {
     }
 }
 
+impl From<Int> for Value {
+    fn from(val: Int) -> Self {
+        Value::Int(val)
+    }
+}
+
 impl From<bool> for Value {
     fn from(val: bool) -> Self {
         Value::Boolean(val)
@@ -190,19 +291,97 @@ impl Iterator for ChunkIterator<'_> {
     }
 }
 
+/// Hashes values consistently with `==`, so they can be used as map keys. Ints, BigInts
+/// and whole floats that are equal all hash as the same integer.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Int(num) => hash_int(*num, state),
+            Value::BigInt(num) => match num.to_int() {
+                Some(int) => hash_int(int, state),
+                None => hash_big_int(num, state),
+            },
+            Value::Number(num) if num.is_finite() && num.fract() == 0.0 => {
+                // Every float in this range is a whole number that converts to an int exactly.
+                if *num >= Int::MIN as Number && *num < -(Int::MIN as Number) {
+                    hash_int(*num as Int, state);
+                } else if let Some(big_int) = BigInt::from_float(*num) {
+                    hash_big_int(&big_int, state);
+                }
+            }
+            _ => {
+                mem::discriminant(self).hash(state);
+                match self {
+                    Value::Nil => {}
+                    Value::Number(num) => num.to_bits().hash(state),
+                    Value::Boolean(val) => val.hash(state),
+                    // Strings are equal when their contents are, whether or not they're interned.
+                    Value::String(string) => (**string).hash(state),
+                    Value::BoundMethod(bound) => {
+                        bound.method.hash(state);
+                        bound.receiver.hash(state);
+                    }
+                    // Enum variants carrying values compare by their fields, which can't be
+                    // hashed in a stable order, so only their variant is hashed.
+                    Value::Instance(instance) if instance.class.variant.is_some() => {
+                        instance.class.hash(state)
+                    }
+                    Value::Int(_) | Value::BigInt(_) => unreachable!(),
+                    Value::Function(obj) => obj.hash(state),
+                    Value::NativeFunction(obj) => obj.hash(state),
+                    Value::Closure(obj) => obj.hash(state),
+                    Value::Class(obj) => obj.hash(state),
+                    Value::Instance(obj) => obj.hash(state),
+                    Value::Module(obj) => obj.hash(state),
+                    Value::List(obj) => obj.hash(state),
+                    Value::Generator(obj) | Value::Fiber(obj) => obj.hash(state),
+                }
+            }
+        }
+    }
+}
+
+// Whole numbers of every kind hash under the int discriminant.
+fn hash_int<H: Hasher>(int: Int, state: &mut H) {
+    mem::discriminant(&Value::Int(0)).hash(state);
+    int.hash(state);
+}
+
+fn hash_big_int<H: Hasher>(big_int: &BigInt, state: &mut H) {
+    mem::discriminant(&Value::Int(0)).hash(state);
+    big_int.hash(state);
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Value::Nil => write!(f, "nil"),
-            Value::Number(num) => write!(f, "{}", num),
+            Value::Number(num) => {
+                // Whole floats keep a '.0' so they don't read as ints.
+                let formatted = num.to_string();
+                if formatted.contains(['.', 'e', 'i', 'N']) {
+                    write!(f, "{}", formatted)
+                } else {
+                    write!(f, "{}.0", formatted)
+                }
+            }
+            Value::Int(num) => write!(f, "{}", num),
+            Value::BigInt(num) => write!(f, "{}", **num),
             Value::Boolean(val) => write!(f, "{}", val),
             Value::String(string) => write!(f, "{}", string),
             Value::Function(lox_fun) => write!(f, "{}", lox_fun),
             Value::NativeFunction(lox_fun) => write!(f, "{}", lox_fun),
             Value::Closure(lox_closure) =>write!(f, "{}", lox_closure.function),
             Value::Class(class) => write!(f, "{:?}", class),
-            Value::Instance(instance) => write!(f, "{:?}", instance),
+            Value::Instance(instance) => match &instance.class.variant {
+                Some(variant) => instance.fmt_variant(variant, f),
+                None => write!(f, "{:?}", instance),
+            },
             Value::BoundMethod(bound_method) =>write!(f, "{}", bound_method.method.function),
+            Value::Module(module) => write!(f, "{}", module),
+            Value::List(list) => write!(f, "{}", list),
+            Value::Generator(generator) => write!(f, "{}", generator),
+            Value::Fiber(fiber) => write!(f, "{}", fiber),
         }
     }
 }
@@ -272,6 +451,7 @@ impl Chunk {
 
         let extension = match instr {
             Instruction::DefineGlobal(var_index)
+            | Instruction::DefineConstGlobal(var_index)
             | Instruction::GetGlobal(var_index)
             | Instruction::SetGlobal(var_index)
             | Instruction::LoadConstant(var_index) => format!("{{value = {}}}", self.get_value(*var_index)),
@@ -406,3 +586,39 @@ impl Error for PlaceholderError {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    use crate::{bigint::BigInt, heap::Heap};
+
+    use super::Value;
+
+    fn hash_of(value: &Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn equal_numbers_hash_alike() {
+        let heap = Heap::new();
+
+        assert_eq!(hash_of(&Value::Int(42)), hash_of(&Value::Number(42.0)));
+        assert_eq!(hash_of(&Value::Int(0)), hash_of(&Value::Number(-0.0)));
+        assert_eq!(
+            hash_of(&Value::Int(42)),
+            hash_of(&Value::BigInt(heap.manage(BigInt::from_int(42))))
+        );
+
+        let big = BigInt::parse("1180591620717411303424", 10).unwrap().into_value(&heap);
+        assert!(matches!(big, Value::BigInt(_)));
+        assert_eq!(hash_of(&big), hash_of(&Value::Number(2f64.powi(70))));
+
+        let other = BigInt::parse("1180591620717411303425", 10).unwrap().into_value(&heap);
+        assert_ne!(hash_of(&big), hash_of(&other));
+        assert_ne!(hash_of(&Value::Int(1)), hash_of(&Value::Number(1.5)));
+    }
+}
@@ -7,6 +7,12 @@ pub enum Precedence {
     // =
     Assignment,
 
+    // ?:
+    Conditional,
+
+    // ??
+    NilCoalescing,
+
     // or
     Or,
 
@@ -28,7 +34,7 @@ pub enum Precedence {
     // !, -
     Unary,
 
-    // ., ()
+    // ., ?., ()
     Call,
 
     Primary,
@@ -39,13 +45,15 @@ impl Precedence {
         use Precedence::*;
         match self {
             None => Assignment,
-            Assignment => Or,
+            Assignment => Conditional,
+            Conditional => NilCoalescing,
+            NilCoalescing => Or,
             Or => And,
             And => Equality,
             Equality => Comparison,
             Comparison => Term,
             Term => Factor,
-            Factor => Term,
+            Factor => Unary,
             Unary => Call,
             Call => Primary,
             Primary => panic!("There is not precdence greater than Precedence::Primary."),
@@ -136,13 +144,43 @@ const VARIABLE_RULE: ParseRule = ParseRule {
 const AND_RULE: ParseRule = ParseRule {
     prefix: None,
     infix: Some(&|this: &mut Compiler, _assign: bool| this.and()),
-    curr_prec: Precedence::Comparison,
+    curr_prec: Precedence::And,
 };
 
 const OR_RULE: ParseRule = ParseRule {
     prefix: None,
     infix: Some(&|this: &mut Compiler, _assign: bool| this.or()),
-    curr_prec: Precedence::Comparison,
+    curr_prec: Precedence::Or,
+};
+
+const QUESTION_DOT_RULE: ParseRule = ParseRule {
+    prefix: None,
+    infix: Some(&|this: &mut Compiler, _assign: bool| this.optional_dot()),
+    curr_prec: Precedence::Call,
+};
+
+const QUESTION_QUESTION_RULE: ParseRule = ParseRule {
+    prefix: None,
+    infix: Some(&|this: &mut Compiler, _assign: bool| this.nil_coalescing()),
+    curr_prec: Precedence::NilCoalescing,
+};
+
+const QUESTION_RULE: ParseRule = ParseRule {
+    prefix: None,
+    infix: Some(&|this: &mut Compiler, _assign: bool| this.conditional()),
+    curr_prec: Precedence::Conditional,
+};
+
+const INCREMENT_RULE: ParseRule = ParseRule {
+    prefix: Some(&|this: &mut Compiler, _assign: bool| this.prefix_update()),
+    infix: None,
+    curr_prec: Precedence::None,
+};
+
+const LEFT_BRACKET_RULE: ParseRule = ParseRule {
+    prefix: Some(&|this: &mut Compiler, assign: bool| this.list(assign)),
+    infix: Some(&|this: &mut Compiler, assign: bool| this.index(assign)),
+    curr_prec: Precedence::Call,
 };
 
 const DOT_RULE: ParseRule = ParseRule {
@@ -157,6 +195,18 @@ const THIS_RULE: ParseRule = ParseRule {
     curr_prec: Precedence::None,
 };
 
+const FUN_RULE: ParseRule = ParseRule {
+    prefix: Some(&|this: &mut Compiler, _assign: bool| this.lambda()),
+    infix: None,
+    curr_prec: Precedence::None,
+};
+
+const YIELD_RULE: ParseRule = ParseRule {
+    prefix: Some(&|this: &mut Compiler, _assign: bool| this.yield_expression()),
+    infix: None,
+    curr_prec: Precedence::None,
+};
+
 const SUPER_RULE: ParseRule = ParseRule {
     prefix: Some(&|this: &mut Compiler, _assign: bool| this.super_()),
     infix: None,
@@ -172,7 +222,7 @@ pub fn parse_rule(token_type: TokenType) -> &'static ParseRule {
         TokenType::Number => &NUMBER_RULE,
         TokenType::False | TokenType::Nil | TokenType::True => &LITERAL_RULE,
         TokenType::Bang => &BANG_RULE,
-        TokenType::BangEqual | TokenType::EqualEqual => &EQUALITY_RULE,
+        TokenType::BangEqual | TokenType::EqualEqual | TokenType::Is => &EQUALITY_RULE,
         TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
             &COMPARISON_RULE
         }
@@ -181,8 +231,15 @@ pub fn parse_rule(token_type: TokenType) -> &'static ParseRule {
         TokenType::And => &AND_RULE,
         TokenType::Or => &OR_RULE,
         TokenType::Dot => &DOT_RULE,
+        TokenType::LeftBracket => &LEFT_BRACKET_RULE,
         TokenType::This => &THIS_RULE,
         TokenType::Super => &SUPER_RULE,
+        TokenType::Fun => &FUN_RULE,
+        TokenType::Yield => &YIELD_RULE,
+        TokenType::Question => &QUESTION_RULE,
+        TokenType::QuestionDot => &QUESTION_DOT_RULE,
+        TokenType::QuestionQuestion => &QUESTION_QUESTION_RULE,
+        TokenType::PlusPlus | TokenType::MinusMinus => &INCREMENT_RULE,
         _ => &PLACEHOLDER_PARSERULE,
     }
 }
@@ -1,18 +1,44 @@
 use crate::interpreter::{Interpreter, InterpreterResult};
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
 use std::process;
 
 pub fn run_file(file_path: &str) {
-    let mut file = File::open(file_path).expect("Failed to open file");
+    let content = read_file(file_path);
+    let mut interpreter = Interpreter::new();
 
-    let mut content = String::new();
-    file.read_to_string(&mut content);
+    let result = interpreter.interpret(&content, Path::new(file_path));
+    exit_on_error(result);
+}
+
+/// Runs a file's test blocks after running the file itself, as `lox test file.lox` does.
+pub fn test_file(file_path: &str) {
+    let content = read_file(file_path);
+    let mut interpreter = Interpreter::new();
+
+    let result = interpreter.test(&content, Path::new(file_path));
+    exit_on_error(result);
+}
 
+/// Compiles a file without running it, as `lox check [--types] file.lox` does.
+pub fn check_file(file_path: &str, check_types: bool) {
+    let content = read_file(file_path);
     let mut interpreter = Interpreter::new();
 
-    let result = interpreter.interpret(&content);
+    let result = interpreter.check(&content, check_types);
+    exit_on_error(result);
+}
+
+fn read_file(file_path: &str) -> String {
+    let mut file = File::open(file_path).expect("Failed to open file");
 
+    let mut content = String::new();
+    file.read_to_string(&mut content);
+    content
+}
+
+fn exit_on_error(result: InterpreterResult) {
     match result {
         InterpreterResult::CompileError => process::exit(65),
         InterpreterResult::RuntimeError => process::exit(70),
@@ -24,6 +50,10 @@ pub fn run_file(file_path: &str) {
 
 const HISTORY_SAVE_PATH: &str = ".lox_history";
 
+// Imports typed at the repl are resolved relative to the working directory.
+#[cfg(feature = "repl")]
+const REPL_MODULE_NAME: &str = "repl";
+
 #[cfg(feature = "repl")]
 pub fn repl() {
         use rustyline::{error::ReadlineError, Config};
@@ -45,7 +75,7 @@ pub fn repl() {
                 Ok(line) => {
                     rl.add_history_entry(line.as_str());
                     println!("Printed line: {}", line);
-                    interpreter.interpret(&line);
+                    interpreter.interpret(&line, Path::new(REPL_MODULE_NAME));
                 }
                 Err(ReadlineError::Interrupted) => {
                     println!("CTRL-C");
@@ -1,5 +1,6 @@
 use std::{iter::Peekable, str::CharIndices};
 
+#[derive(Clone)]
 pub struct Scanner<'a> {
     source: &'a str,
     curr: Peekable<CharIndices<'a>>,
@@ -48,7 +49,7 @@ impl<'a> Scanner<'a> {
     fn can_match_alphanumeric(&mut self) -> bool {
         match self.curr.peek() {
             None => false,
-            Some((_sz, char_)) => char_.is_alphanumeric(),
+            Some((_sz, char_)) => char_.is_alphanumeric() || *char_ == '_',
         }
     }
 
@@ -66,8 +67,13 @@ impl<'a> Scanner<'a> {
     }
 
     fn error_token(&self, msg: &'static str) -> Token<'a> {
+        self.error_token_at(msg, self.line)
+    }
+
+    /// Reports an error for a construct that began on `line`, e.g. an unterminated string.
+    fn error_token_at(&self, msg: &'static str, line: usize) -> Token<'a> {
         Token {
-            line: self.line,
+            line,
             kind: TokenType::Error,
             description: msg,
         }
@@ -77,7 +83,8 @@ impl<'a> Scanner<'a> {
         None == self.curr.peek()
     }
 
-    fn skip_whitespace(&mut self) {
+    /// Skips whitespace and comments, returning an error token for an unterminated block comment.
+    fn skip_whitespace(&mut self) -> Option<Token<'a>> {
         loop {
             if let Some((_sz, a)) = self.curr.peek() {
                 match a {
@@ -90,18 +97,22 @@ impl<'a> Scanner<'a> {
                         // // Why break here? What if next line starts with whitespace?
                         // break;
                     }
-                    '/' => {
-                        if let Some((_, '/')) = self.curr.peek_twice() {
+                    '/' => match self.curr.peek_twice() {
+                        Some((_, '/')) => {
                             self.curr.next();
                             self.curr.next();
                             while !self.is_at_end() && !self.match_char('\n') {
                                 self.curr.next();
                             }
-                        } else {
-                            break;
                         }
-                        
-                    }
+                        Some((_, '*')) => {
+                            let error = self.block_comment();
+                            if error.is_some() {
+                                return error;
+                            }
+                        }
+                        _ => break,
+                    },
                     _ => {
                         break;
                     }
@@ -110,18 +121,60 @@ impl<'a> Scanner<'a> {
                 break;
             }
         }
+
+        None
     }
 
-    fn string(&mut self) -> Token<'a> {
-        while !self.is_at_end() && !self.match_char('"') {
-            self.curr.next();
+    /// Skips a '/* ... */' comment, which may contain nested block comments.
+    fn block_comment(&mut self) -> Option<Token<'a>> {
+        let start_line = self.line;
+        self.curr.next();
+        self.curr.next();
+
+        let mut depth = 1;
+        while depth > 0 {
+            match self.curr.next() {
+                None => return Some(self.error_token_at("Unterminated block comment.", start_line)),
+                Some((_, '\n')) => self.line += 1,
+                Some((_, '/')) if self.consume_if('*') => depth += 1,
+                Some((_, '*')) if self.consume_if('/') => depth -= 1,
+                _ => {}
+            }
         }
 
-        if self.is_at_end() {
-            return self.error_token("Unterminated string.");
+        None
+    }
+
+    /// Scans the rest of a string after its opening quote. Raw strings, prefixed with 'r',
+    /// don't treat backslashes as escapes, while triple quoted strings may contain lone quotes.
+    /// The compiler strips the quotes and decodes escapes.
+    fn string(&mut self, raw: bool) -> Token<'a> {
+        let start_line = self.line;
+
+        let triple_quoted = self.match_char('"') && matches!(self.curr.peek_twice(), Some((_, '"')));
+        if triple_quoted {
+            self.curr.next();
+            self.curr.next();
         }
 
-        self.curr.next();
+        loop {
+            match self.curr.next() {
+                None => return self.error_token_at("Unterminated string.", start_line),
+                Some((_, '"')) if !triple_quoted => break,
+                Some((_, '"')) if self.match_char('"') && matches!(self.curr.peek_twice(), Some((_, '"'))) => {
+                    self.curr.next();
+                    self.curr.next();
+                    break;
+                }
+                Some((_, '\n')) => self.line += 1,
+                Some((_, '\\')) if !raw => {
+                    if let Some((_, '\n')) = self.curr.next() {
+                        self.line += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
 
         return self.make_token(TokenType::String);
     }
@@ -135,11 +188,33 @@ impl<'a> Scanner<'a> {
         &self.source[self.start..end]
     }
 
-    fn digit(&mut self) -> Token<'a> {
-        while !self.is_at_end() && self.can_match_digit() {
-            self.curr.next();
+    /// Scans a number literal whose first digit was `first`. Hexadecimal and binary literals
+    /// use '0x' and '0b' prefixes, and any run of digits may be split up by underscores.
+    fn digit(&mut self, first: char) -> Token<'a> {
+        if first == '0' {
+            let radix = match self.curr.peek() {
+                Some((_, 'x')) | Some((_, 'X')) => Some(16),
+                Some((_, 'b')) | Some((_, 'B')) => Some(2),
+                _ => None,
+            };
+
+            if let Some(radix) = radix {
+                self.curr.next();
+                if !self.can_match_digit_in(radix) {
+                    return self.error_token(if radix == 16 {
+                        "Expect hexadecimal digits after '0x'."
+                    } else {
+                        "Expect binary digits after '0b'."
+                    });
+                }
+
+                self.digits(radix);
+                return self.finish_number(radix);
+            }
         }
 
+        self.digits(BASE);
+
         let has_dot = self.match_char('.');
 
         let is_fraction = has_dot && match self.curr.peek_twice() {
@@ -149,12 +224,56 @@ impl<'a> Scanner<'a> {
 
         if is_fraction {
             self.curr.next();
-            while self.can_match_digit() {
+            self.digits(BASE);
+        }
+
+        if self.consume_if('e') || self.consume_if('E') {
+            if !self.consume_if('+') {
+                self.consume_if('-');
+            }
+
+            if !self.can_match_digit() {
+                return self.error_token("Expect digits in number exponent.");
+            }
+            self.digits(BASE);
+        }
+
+        self.finish_number(BASE)
+    }
+
+    fn can_match_digit_in(&mut self, radix: u32) -> bool {
+        match self.curr.peek() {
+            None => false,
+            Some((_sz, char_)) => char_.is_digit(radix),
+        }
+    }
+
+    fn digits(&mut self, radix: u32) {
+        while self.can_match_digit_in(radix) || self.match_char('_') {
+            self.curr.next();
+        }
+    }
+
+    fn finish_number(&mut self, radix: u32) -> Token<'a> {
+        if self.can_match_alphanumeric() {
+            while self.can_match_alphanumeric() {
                 self.curr.next();
             }
+            return self.error_token("Invalid digit in number literal.");
+        }
+
+        let literal = self.get_curr_string().as_bytes();
+        let is_digit = |i: usize| literal.get(i).map_or(false, |c| (*c as char).is_digit(radix));
+        let misplaced_separator = literal
+            .iter()
+            .enumerate()
+            .any(|(i, c)| *c == b'_' && !(i > 0 && is_digit(i - 1) && is_digit(i + 1)));
+
+        if misplaced_separator {
+            return self.error_token("Underscores in number literals must be between digits.");
         }
 
-        return self.make_token(TokenType::Number);
+        self.make_token(TokenType::Number)
     }
 
     fn identifier(&mut self) -> Token<'a> {
@@ -178,7 +297,9 @@ impl<'a> Scanner<'a> {
 impl<'a> Iterator for Scanner<'a> {
     type Item = Token<'a>;
     fn next(&mut self) -> Option<Self::Item> {
-        self.skip_whitespace();
+        if let Some(error) = self.skip_whitespace() {
+            return Some(error);
+        }
 
         self.start = match self.curr.peek() {
             None => self.source.len(),
@@ -192,10 +313,14 @@ impl<'a> Iterator for Scanner<'a> {
         let (_sz, c) = self.curr.next().unwrap();
 
         if c.is_digit(BASE) {
-            return Some(self.digit());
+            return Some(self.digit(c));
         }
 
-        if c.is_alphabetic() {
+        if c == 'r' && self.consume_if('"') {
+            return Some(self.string(true));
+        }
+
+        if c.is_alphabetic() || c == '_' {
             return Some(self.identifier());
         }
 
@@ -205,12 +330,64 @@ impl<'a> Iterator for Scanner<'a> {
             '{' => self.make_token(TokenType::LeftBrace),
             '}' => self.make_token(TokenType::RightBrace),
             ',' => self.make_token(TokenType::Comma),
-            '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
+            '.' => {
+                if self.can_match_digit() {
+                    self.error_token("Number literals can't start with '.', add a leading 0.")
+                } else if !self.consume_if('.') {
+                    self.make_token(TokenType::Dot)
+                } else if self.consume_if('.') {
+                    self.make_token(TokenType::Ellipsis)
+                } else {
+                    self.error_token("Expect '...' before rest parameter.")
+                }
+            }
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
+            '-' => {
+                if self.consume_if('-') {
+                    self.make_token(TokenType::MinusMinus)
+                } else if self.consume_if('=') {
+                    self.make_token(TokenType::MinusEqual)
+                } else if self.consume_if('>') {
+                    self.make_token(TokenType::ThinArrow)
+                } else {
+                    self.make_token(TokenType::Minus)
+                }
+            }
+            '+' => {
+                if self.consume_if('+') {
+                    self.make_token(TokenType::PlusPlus)
+                } else if self.consume_if('=') {
+                    self.make_token(TokenType::PlusEqual)
+                } else {
+                    self.make_token(TokenType::Plus)
+                }
+            }
             ';' => self.make_token(TokenType::SemiColon),
-            '/' => self.make_token(TokenType::Slash),
-            '*' => self.make_token(TokenType::Star),
+            '/' => {
+                if self.consume_if('=') {
+                    self.make_token(TokenType::SlashEqual)
+                } else {
+                    self.make_token(TokenType::Slash)
+                }
+            }
+            '*' => {
+                if self.consume_if('=') {
+                    self.make_token(TokenType::StarEqual)
+                } else {
+                    self.make_token(TokenType::Star)
+                }
+            }
+            '?' => {
+                if self.consume_if('.') {
+                    self.make_token(TokenType::QuestionDot)
+                } else if self.consume_if('?') {
+                    self.make_token(TokenType::QuestionQuestion)
+                } else {
+                    self.make_token(TokenType::Question)
+                }
+            }
+            ':' => self.make_token(TokenType::Colon),
             '!' => {
                 if self.consume_if('=') {
                     self.make_token(TokenType::BangEqual)
@@ -221,6 +398,8 @@ impl<'a> Iterator for Scanner<'a> {
             '=' => {
                 if self.consume_if('=') {
                     self.make_token(TokenType::EqualEqual)
+                } else if self.consume_if('>') {
+                    self.make_token(TokenType::Arrow)
                 } else {
                     self.make_token(TokenType::Equal)
                 }
@@ -239,7 +418,7 @@ impl<'a> Iterator for Scanner<'a> {
                     self.make_token(TokenType::Less)
                 }
             }
-            '"' => self.string(),
+            '"' => self.string(false),
             _ => self.error_token("Unexpected character."),
         };
 
@@ -268,6 +447,12 @@ pub enum TokenType {
     SemiColon,
     Slash,
     Star,
+    Question,
+    QuestionDot,
+    QuestionQuestion,
+    Colon,
+    LeftBracket,
+    RightBracket,
 
     // One or two Character tokens
     Bang,
@@ -278,6 +463,15 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    Arrow,
+    ThinArrow,
+    Ellipsis,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PlusPlus,
+    MinusMinus,
 
     // Literals.
     Identifier,
@@ -287,6 +481,8 @@ pub enum TokenType {
     //
     And,
     Class,
+    Const,
+    Match,
     Else,
     False,
     For,
@@ -301,6 +497,19 @@ pub enum TokenType {
     True,
     Var,
     While,
+    Throw,
+    Try,
+    Catch,
+    Finally,
+    Import,
+    From,
+    Yield,
+    In,
+    Is,
+    Enum,
+    Trait,
+    With,
+    Assert,
 
     Error,
     EOF,
@@ -338,24 +547,69 @@ fn identifier_type(ident: &str) -> TokenType {
     let remaining = chars.as_str();
 
     match c {
-        'a' => check_match(remaining, "nd", TokenType::And),
-        'c' => check_match(remaining, "lass", TokenType::Class),
-        'e' => check_match(remaining, "lse", TokenType::Else),
-        'i' => check_match(remaining, "f", TokenType::If),
+        'a' => {
+            if remaining == "nd" {
+                TokenType::And
+            } else {
+                check_match(remaining, "ssert", TokenType::Assert)
+            }
+        }
+        'c' => {
+            let nc = chars.next();
+            let remaining = chars.as_str();
+            if nc.is_some() {
+                match nc.unwrap() {
+                    'l' => check_match(remaining, "ass", TokenType::Class),
+                    'a' => check_match(remaining, "tch", TokenType::Catch),
+                    'o' => check_match(remaining, "nst", TokenType::Const),
+                    _ => TokenType::Identifier,
+                }
+            } else {
+                TokenType::Identifier
+            }
+        }
+        'e' => {
+            if remaining == "num" {
+                TokenType::Enum
+            } else {
+                check_match(remaining, "lse", TokenType::Else)
+            }
+        }
+        'i' => {
+            if remaining == "f" {
+                TokenType::If
+            } else if remaining == "n" {
+                TokenType::In
+            } else if remaining == "s" {
+                TokenType::Is
+            } else {
+                check_match(remaining, "mport", TokenType::Import)
+            }
+        }
+        'm' => check_match(remaining, "atch", TokenType::Match),
         'n' => check_match(remaining, "il", TokenType::Nil),
         'o' => check_match(remaining, "r", TokenType::Or),
         'p' => check_match(remaining, "rint", TokenType::Print),
         'r' => check_match(remaining, "eturn", TokenType::Return),
         's' => check_match(remaining, "uper", TokenType::Super),
         'v' => check_match(remaining, "ar", TokenType::Var),
-        'w' => check_match(remaining, "hile", TokenType::While),
+        'w' => {
+            if remaining == "ith" {
+                TokenType::With
+            } else {
+                check_match(remaining, "hile", TokenType::While)
+            }
+        }
+        'y' => check_match(remaining, "ield", TokenType::Yield),
         'f' => {
             let nc = chars.next();
             let remaining = chars.as_str();
             if nc.is_some() {
                 match nc.unwrap() {
                     'a' => check_match(remaining, "lse", TokenType::False),
+                    'i' => check_match(remaining, "nally", TokenType::Finally),
                     'o' => check_match(remaining, "r", TokenType::For),
+                    'r' => check_match(remaining, "om", TokenType::From),
                     'u' => check_match(remaining, "n", TokenType::Fun),
                     _ => TokenType::Identifier,
                 }
@@ -368,8 +622,22 @@ fn identifier_type(ident: &str) -> TokenType {
             let remaining = chars.as_str();
             if nc.is_some() {
                 match nc.unwrap() {
-                    'h' => check_match(remaining, "is", TokenType::This),
-                    'r' => check_match(remaining, "ue", TokenType::True),
+                    'h' => {
+                        if remaining == "is" {
+                            TokenType::This
+                        } else {
+                            check_match(remaining, "row", TokenType::Throw)
+                        }
+                    }
+                    'r' => {
+                        if remaining == "ue" {
+                            TokenType::True
+                        } else if remaining == "ait" {
+                            TokenType::Trait
+                        } else {
+                            check_match(remaining, "y", TokenType::Try)
+                        }
+                    }
                     _ => TokenType::Identifier,
                 }
             } else {
@@ -380,6 +648,18 @@ fn identifier_type(ident: &str) -> TokenType {
     }
 }
 
+impl<'a> Scanner<'a> {
+    /// Returns the source text from the start of `first` to the end of `last`, which must
+    /// both have been scanned from this source.
+    pub fn source_between(&self, first: &Token<'a>, last: &Token<'a>) -> &'a str {
+        let source_start = self.source.as_ptr() as usize;
+        let start = first.description.as_ptr() as usize - source_start;
+        let end = last.description.as_ptr() as usize + last.description.len() - source_start;
+
+        &self.source[start..end]
+    }
+}
+
 impl Token<'_> {
     pub fn placeholder() -> Self {
         Token {
@@ -0,0 +1,582 @@
+//! Static types for `lox check --types`. When type checking is enabled the compiler works
+//! out the type of each expression as it compiles it and reports the ones that don't fit
+//! the optional annotations, or the operators and calls they're used with, as diagnostics.
+//! None of this changes the bytecode, annotations are ignored when a script runs.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display, Formatter},
+    rc::Rc,
+};
+
+use crate::{
+    object::{arity_error, Arity},
+    opcodes::Instruction,
+    scanner::Token,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type<'a> {
+    /// Any value, used wherever the checker can't tell.
+    Any,
+    Nil,
+    Bool,
+    Int,
+    Float,
+    /// An int or a float.
+    Number,
+    String,
+    List,
+    /// A function, along with its signature when it's known.
+    Function(Option<Rc<Signature<'a>>>),
+    /// The class object itself, as opposed to its instances.
+    Class(&'a str),
+    Instance(&'a str),
+    /// A value of the inner type or nil, written `Type?`.
+    Optional(Box<Type<'a>>),
+}
+
+impl Default for Type<'_> {
+    fn default() -> Self {
+        Type::Any
+    }
+}
+
+impl Type<'_> {
+    fn is_number(&self) -> bool {
+        matches!(self, Type::Int | Type::Float | Type::Number)
+    }
+
+    /// Whether the checker leaves operators on values of this type alone. Instances can
+    /// overload operators and optional values are only known not to be nil at runtime.
+    fn is_dynamic(&self) -> bool {
+        matches!(self, Type::Any | Type::Instance(_) | Type::Optional(_))
+    }
+}
+
+impl Display for Type<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "Any"),
+            Type::Nil => write!(f, "Nil"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Int => write!(f, "Int"),
+            Type::Float => write!(f, "Float"),
+            Type::Number => write!(f, "Number"),
+            Type::String => write!(f, "String"),
+            Type::List => write!(f, "List"),
+            Type::Function(_) => write!(f, "Function"),
+            Type::Class(name) => write!(f, "class {}", name),
+            Type::Instance(name) => write!(f, "{}", name),
+            Type::Optional(ty) => write!(f, "{}?", ty),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Signature<'a> {
+    pub name: String,
+    /// The types of the named parameters, a rest parameter takes any number of values.
+    pub params: Vec<Type<'a>>,
+    pub arity: Arity,
+    pub optional_arity: Arity,
+    pub has_rest: bool,
+    pub return_type: Type<'a>,
+}
+
+impl<'a> Signature<'a> {
+    fn built_in(name: &str, params: Vec<Type<'a>>, optional_arity: Arity, return_type: Type<'a>) -> Rc<Self> {
+        Rc::new(Self {
+            name: name.to_owned(),
+            arity: params.len() as Arity - optional_arity,
+            params,
+            optional_arity,
+            has_rest: false,
+            return_type,
+        })
+    }
+
+    fn accepts(&self, arg_count: Arity) -> bool {
+        arg_count >= self.arity && (self.has_rest || arg_count <= self.arity + self.optional_arity)
+    }
+}
+
+/// The type of a variable or field. Annotated types are checked whenever a value is
+/// assigned, while inferred ones widen to fit whatever gets assigned.
+#[derive(Debug, Clone, Default)]
+pub struct VarType<'a> {
+    pub ty: Type<'a>,
+    pub annotated: bool,
+}
+
+impl<'a> VarType<'a> {
+    pub fn annotated(ty: Type<'a>) -> Self {
+        Self { ty, annotated: true }
+    }
+
+    /// Variables that start out nil are usually assigned something else later.
+    pub fn inferred(ty: Type<'a>) -> Self {
+        let ty = if ty == Type::Nil { Type::Any } else { ty };
+        Self { ty, annotated: false }
+    }
+}
+
+/// What the checker knows about a class or trait.
+#[derive(Default)]
+pub struct ClassInfo<'a> {
+    pub superclass: Option<&'a str>,
+    pub traits: Vec<&'a str>,
+    pub is_trait: bool,
+    pub fields: HashMap<&'a str, VarType<'a>>,
+    pub getters: HashMap<&'a str, Type<'a>>,
+    pub methods: HashMap<&'a str, Rc<Signature<'a>>>,
+    pub static_methods: HashMap<&'a str, Rc<Signature<'a>>>,
+}
+
+pub struct Diagnostic {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Type error: {}", self.line, self.message)
+    }
+}
+
+/// Classes the prelude defines for every script.
+const PRELUDE_CLASSES: &[&str] = &["Error", "Fiber"];
+
+/// Owner of the expression type before the function compiling an operand has set it.
+const NO_OWNER: usize = 0;
+
+pub struct TypeChecker<'a> {
+    pub globals: HashMap<&'a str, VarType<'a>>,
+    pub classes: HashMap<&'a str, ClassInfo<'a>>,
+    /// Classes, traits and enums declared so far, along with names imported from modules.
+    type_names: HashSet<&'a str>,
+    /// Class names used in annotations, which may be declared after they're used.
+    annotation_names: Vec<Token<'a>>,
+    diagnostics: Vec<Diagnostic>,
+    /// The type of the expression compiled last, set by the expression that
+    /// `expression_owner` identifies.
+    expression: Type<'a>,
+    expression_owner: usize,
+    current_expression: usize,
+    expression_count: usize,
+}
+
+impl<'a> TypeChecker<'a> {
+    pub fn new() -> Self {
+        Self {
+            globals: HashMap::new(),
+            classes: HashMap::new(),
+            type_names: PRELUDE_CLASSES.iter().copied().collect(),
+            annotation_names: Vec::new(),
+            diagnostics: Vec::new(),
+            expression: Type::Any,
+            expression_owner: NO_OWNER,
+            current_expression: NO_OWNER,
+            expression_count: NO_OWNER,
+        }
+    }
+
+    /// Starts the expression that the following operands belong to, returning the one it's nested in.
+    pub fn begin_expression(&mut self) -> usize {
+        self.expression_count += 1;
+        std::mem::replace(&mut self.current_expression, self.expression_count)
+    }
+
+    pub fn end_expression(&mut self, enclosing: usize) {
+        self.current_expression = enclosing;
+    }
+
+    /// Called before compiling an operand, whose parse function sets its type.
+    pub fn begin_operand(&mut self) {
+        self.expression_owner = NO_OWNER;
+    }
+
+    /// Called after compiling an operand. Parse functions that don't work out a type, or
+    /// only compiled other expressions, leave the operand's type unknown.
+    pub fn end_operand(&mut self) {
+        if self.expression_owner != self.current_expression {
+            self.set(Type::Any);
+        }
+    }
+
+    pub fn set(&mut self, ty: Type<'a>) {
+        self.expression = ty;
+        self.expression_owner = self.current_expression;
+    }
+
+    pub fn expression_type(&self) -> Type<'a> {
+        self.expression.clone()
+    }
+
+    pub fn error(&mut self, line: usize, message: String) {
+        self.diagnostics.push(Diagnostic { line, message });
+    }
+
+    /// Reports the remaining diagnostics once the whole script is compiled and returns
+    /// them all in line order.
+    pub fn finish(&mut self) -> Vec<Diagnostic> {
+        for token in std::mem::take(&mut self.annotation_names) {
+            if !self.type_names.contains(token.description) {
+                self.error(token.line, format!("Unknown type '{}'.", token.description));
+            }
+        }
+
+        let mut diagnostics = std::mem::take(&mut self.diagnostics);
+        diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+        diagnostics
+    }
+
+    pub fn declare_type_name(&mut self, name: &'a str) {
+        self.type_names.insert(name);
+    }
+
+    /// The type a type name in an annotation stands for.
+    pub fn resolve(&mut self, name: Token<'a>) -> Type<'a> {
+        match name.description {
+            "Any" => Type::Any,
+            "Nil" => Type::Nil,
+            "Bool" => Type::Bool,
+            "Int" => Type::Int,
+            "Float" => Type::Float,
+            "Number" => Type::Number,
+            "String" => Type::String,
+            "List" => Type::List,
+            "Function" => Type::Function(None),
+            class_name => {
+                self.annotation_names.push(name);
+                Type::Instance(class_name)
+            }
+        }
+    }
+
+    pub fn is_assignable(&self, to: &Type<'a>, from: &Type<'a>) -> bool {
+        match (to, from) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (to, from) if to == from => true,
+            // Unknown type names are reported once, rather than at every use.
+            (Type::Instance(to), _) if !self.type_names.contains(to) => true,
+            (Type::Number, from) => from.is_number(),
+            (Type::Float, Type::Int) => true,
+            (Type::Optional(_), Type::Nil) => true,
+            (Type::Optional(to), Type::Optional(from)) => self.is_assignable(to, from),
+            (Type::Optional(to), from) => self.is_assignable(to, from),
+            (Type::Function(_), Type::Function(_)) => true,
+            // Classes the checker hasn't seen, like imported ones, could be anything.
+            (Type::Instance(to), Type::Instance(from)) => self.is_subclass(from, to) != Some(false),
+            _ => false,
+        }
+    }
+
+    /// The narrowest type that both types are assignable to.
+    pub fn join(&self, a: &Type<'a>, b: &Type<'a>) -> Type<'a> {
+        match (a, b) {
+            (a, b) if a == b => a.clone(),
+            (Type::Any, _) | (_, Type::Any) => Type::Any,
+            (Type::Nil, Type::Optional(ty)) | (Type::Optional(ty), Type::Nil) => Type::Optional(ty.clone()),
+            (Type::Nil, ty) | (ty, Type::Nil) => Type::Optional(Box::new(ty.clone())),
+            (Type::Optional(a), Type::Optional(b)) => Type::Optional(Box::new(self.join(a, b))),
+            (Type::Optional(a), b) | (b, Type::Optional(a)) => Type::Optional(Box::new(self.join(a, b))),
+            (a, b) if a.is_number() && b.is_number() => Type::Number,
+            (Type::Function(_), Type::Function(_)) => Type::Function(None),
+            (Type::Instance(a_name), Type::Instance(b_name)) => {
+                if self.is_subclass(a_name, b_name) == Some(true) {
+                    b.clone()
+                } else if self.is_subclass(b_name, a_name) == Some(true) {
+                    a.clone()
+                } else {
+                    Type::Any
+                }
+            }
+            _ => Type::Any,
+        }
+    }
+
+    /// Whether `class` is `ancestor`, inherits from it or uses it as a trait, or
+    /// `None` when part of its hierarchy is unknown.
+    fn is_subclass(&self, class: &str, ancestor: &str) -> Option<bool> {
+        if class == ancestor {
+            return Some(true);
+        }
+
+        let info = self.classes.get(class)?;
+        for used_trait in info.traits.iter() {
+            if self.is_subclass(used_trait, ancestor)? {
+                return Some(true);
+            }
+        }
+
+        match info.superclass {
+            Some(superclass) => self.is_subclass(superclass, ancestor),
+            None => Some(false),
+        }
+    }
+
+    /// Finds the first of `class` and its superclasses that `lookup` finds something in.
+    fn lookup<T>(&self, class: &str, lookup: impl Fn(&ClassInfo<'a>) -> Option<T>) -> Option<T> {
+        let info = self.classes.get(class)?;
+        lookup(info).or_else(|| self.lookup(info.superclass?, lookup))
+    }
+
+    /// Whether every method instances of `class` can have is known.
+    fn is_complete(&self, class: &str) -> bool {
+        match self.classes.get(class) {
+            Some(info) if !info.is_trait => info.superclass.map_or(true, |superclass| self.is_complete(superclass)),
+            _ => false,
+        }
+    }
+
+    pub fn field(&self, class: &str, name: &str) -> Option<VarType<'a>> {
+        self.lookup(class, |info| info.fields.get(name).cloned())
+    }
+
+    /// Reports `value` not fitting into `target`, which `description` names.
+    pub fn check_assignable(&mut self, line: usize, target: &Type<'a>, value: &Type<'a>, description: &str) {
+        if !self.is_assignable(target, value) {
+            self.error(line, format!("Can't assign {} to {} of type {}.", value, description, target));
+        }
+    }
+
+    /// Checks an assignment to a variable or field, returning its type afterwards.
+    pub fn assign(&mut self, line: usize, target: &VarType<'a>, value: &Type<'a>, description: &str) -> VarType<'a> {
+        if target.annotated {
+            self.check_assignable(line, &target.ty, value, description);
+            target.clone()
+        } else {
+            VarType::inferred(self.join(&target.ty, value))
+        }
+    }
+
+    /// The type of an arithmetic instruction's result.
+    pub fn arithmetic(&mut self, line: usize, op: &Instruction, lhs: &Type<'a>, rhs: &Type<'a>) -> Type<'a> {
+        if lhs.is_dynamic() || rhs.is_dynamic() {
+            return Type::Any;
+        }
+
+        match (op, lhs, rhs) {
+            (Instruction::Add, Type::String, Type::String) => Type::String,
+            (Instruction::Divide, lhs, rhs) if lhs.is_number() && rhs.is_number() => Type::Float,
+            (_, Type::Int, Type::Int) => Type::Int,
+            (_, Type::Float, rhs) | (_, rhs, Type::Float) if rhs.is_number() => Type::Float,
+            (_, lhs, rhs) if lhs.is_number() && rhs.is_number() => Type::Number,
+            (Instruction::Add, lhs, rhs) => {
+                self.error(line, format!("Operands must both be either numbers or strings, got {} and {}.", lhs, rhs));
+                Type::Any
+            }
+            (_, lhs, rhs) => {
+                self.error(line, format!("Operands must both be numbers, got {} and {}.", lhs, rhs));
+                Type::Any
+            }
+        }
+    }
+
+    /// The type of a `<`, `>`, `<=` or `>=` comparison.
+    pub fn comparison(&mut self, line: usize, lhs: &Type<'a>, rhs: &Type<'a>) -> Type<'a> {
+        let comparable = lhs.is_dynamic()
+            || rhs.is_dynamic()
+            || (lhs.is_number() && rhs.is_number())
+            || (*lhs == Type::String && *rhs == Type::String);
+
+        if !comparable {
+            self.error(line, format!("Operands must both be either numbers or strings, got {} and {}.", lhs, rhs));
+        }
+        Type::Bool
+    }
+
+    pub fn negate(&mut self, line: usize, operand: &Type<'a>) -> Type<'a> {
+        if operand.is_dynamic() {
+            Type::Any
+        } else if operand.is_number() {
+            operand.clone()
+        } else {
+            self.error(line, format!("Operand must be a number, got {}.", operand));
+            Type::Any
+        }
+    }
+
+    /// Checks a call's arguments against `signature`, returning the type of its result.
+    /// `name` is what the call is reported as calling.
+    fn check_signature(&mut self, line: usize, name: &str, signature: &Signature<'a>, args: &[Type<'a>]) -> Type<'a> {
+        if !signature.accepts(args.len() as Arity) {
+            let message = arity_error(signature.arity, signature.optional_arity, signature.has_rest, args.len() as Arity);
+            self.error(line, format!("{} in call to '{}'.", message.trim_end_matches('.'), name));
+        }
+
+        for (index, (param, arg)) in signature.params.iter().zip(args.iter()).enumerate() {
+            if !self.is_assignable(param, arg) {
+                self.error(
+                    line,
+                    format!("Argument {} of '{}' expects {} but got {}.", index + 1, name, param, arg),
+                );
+            }
+        }
+
+        signature.return_type.clone()
+    }
+
+    pub fn call(&mut self, line: usize, callee: &Type<'a>, args: &[Type<'a>]) -> Type<'a> {
+        match callee {
+            Type::Function(Some(signature)) => self.check_signature(line, &signature.name, signature, args),
+            Type::Class(class) => {
+                match self.lookup(class, |info| info.methods.get("init").cloned()) {
+                    Some(initializer) => {
+                        self.check_signature(line, class, &initializer, args);
+                    }
+                    None if self.is_complete(class) && !args.is_empty() => {
+                        self.error(line, format!("Expected 0 arguments but got {} in call to '{}'.", args.len(), class));
+                    }
+                    None => {}
+                }
+                Type::Instance(*class)
+            }
+            Type::Nil | Type::Bool | Type::Int | Type::Float | Type::Number | Type::String | Type::List => {
+                self.error(line, format!("Can only call functions and classes, got {}.", callee));
+                Type::Any
+            }
+            _ => Type::Any,
+        }
+    }
+
+    pub fn property(&mut self, line: usize, object: &Type<'a>, name: &str) -> Type<'a> {
+        match object {
+            Type::Instance(class) => {
+                if let Some(field) = self.field(class, name) {
+                    field.ty
+                } else if let Some(getter) = self.lookup(class, |info| info.getters.get(name).cloned()) {
+                    getter
+                } else if let Some(method) = self.lookup(class, |info| info.methods.get(name).cloned()) {
+                    Type::Function(Some(method))
+                } else {
+                    Type::Any
+                }
+            }
+            Type::Class(class) => self
+                .lookup(class, |info| info.static_methods.get(name).cloned())
+                .map_or(Type::Any, |method| Type::Function(Some(method))),
+            Type::Nil | Type::Bool | Type::Int | Type::Float | Type::Number => {
+                self.error(line, format!("Only instances have properties, got {}.", object));
+                Type::Any
+            }
+            _ => Type::Any,
+        }
+    }
+
+    /// Checks an assignment to a property. Fields that weren't declared in their class'
+    /// body get their type from the values assigned to them.
+    pub fn set_property(&mut self, line: usize, object: &Type<'a>, name: &'a str, value: &Type<'a>) {
+        let class = match object {
+            Type::Instance(class) => *class,
+            Type::Nil | Type::Bool | Type::Int | Type::Float | Type::Number | Type::String | Type::List => {
+                self.error(line, format!("Only instances have fields, got {}.", object));
+                return;
+            }
+            _ => return,
+        };
+
+        let description = format!("field '{}'", name);
+        let field = match self.field(class, name) {
+            Some(field) => self.assign(line, &field, value, &description),
+            None => VarType::inferred(value.clone()),
+        };
+
+        if let Some(info) = self.classes.get_mut(class) {
+            if !info.fields.get(name).map_or(false, |field| field.annotated) {
+                info.fields.insert(name, field);
+            }
+        }
+    }
+
+    /// Checks a method call, returning the type of its result.
+    pub fn invoke(&mut self, line: usize, object: &Type<'a>, name: &str, args: &[Type<'a>]) -> Type<'a> {
+        let method = match object {
+            Type::Instance(class) => {
+                if let Some(field) = self.field(class, name) {
+                    return self.call(line, &field.ty, args);
+                }
+
+                let method = self.lookup(class, |info| info.methods.get(name).cloned());
+                let is_getter = self.lookup(class, |info| info.getters.get(name).map(|_| ())).is_some();
+                if method.is_none() && !is_getter && self.is_complete(class) {
+                    self.error(line, format!("Undefined method '{}' on {}.", name, class));
+                }
+                method
+            }
+            Type::Class(class) => self.lookup(class, |info| info.static_methods.get(name).cloned()),
+            Type::String => {
+                let method = string_method(name);
+                if method.is_none() {
+                    self.error(line, format!("Undefined method '{}' on string.", name));
+                }
+                method
+            }
+            Type::List => {
+                let method = list_method(name);
+                if method.is_none() {
+                    self.error(line, format!("Undefined method '{}' on list.", name));
+                }
+                method
+            }
+            Type::Nil | Type::Bool | Type::Int | Type::Float | Type::Number => {
+                self.error(line, format!("Only instances have methods, got {}.", object));
+                None
+            }
+            _ => None,
+        };
+
+        method.map_or(Type::Any, |method| self.check_signature(line, name, &method, args))
+    }
+
+    /// Adds the methods of the traits a class uses to the ones it defines itself.
+    pub fn use_trait(&mut self, class: &'a str, used_trait: &'a str) {
+        let methods = match self.classes.get(used_trait) {
+            Some(info) => info.methods.clone(),
+            None => HashMap::new(),
+        };
+
+        if let Some(info) = self.classes.get_mut(class) {
+            info.traits.push(used_trait);
+            for (name, method) in methods {
+                info.methods.entry(name).or_insert(method);
+            }
+        }
+    }
+
+    pub fn add_method(&mut self, class: &str, name: &'a str, signature: Rc<Signature<'a>>, is_static: bool) {
+        if let Some(info) = self.classes.get_mut(class) {
+            let methods = if is_static { &mut info.static_methods } else { &mut info.methods };
+            methods.insert(name, signature);
+        }
+    }
+}
+
+fn string_method(name: &str) -> Option<Rc<Signature<'static>>> {
+    let method = match name {
+        "len" => Signature::built_in(name, vec![], 0, Type::Int),
+        "trim" | "upper" | "lower" => Signature::built_in(name, vec![], 0, Type::String),
+        "chars" => Signature::built_in(name, vec![], 0, Type::List),
+        "toNumber" => Signature::built_in(name, vec![], 0, Type::Optional(Box::new(Type::Number))),
+        "indexOf" => Signature::built_in(name, vec![Type::String], 0, Type::Int),
+        "split" => Signature::built_in(name, vec![Type::String], 0, Type::List),
+        "startsWith" => Signature::built_in(name, vec![Type::String], 0, Type::Bool),
+        "substring" => Signature::built_in(name, vec![Type::Int, Type::Int], 1, Type::String),
+        "replace" => Signature::built_in(name, vec![Type::String, Type::String], 0, Type::String),
+        _ => return None,
+    };
+
+    Some(method)
+}
+
+fn list_method(name: &str) -> Option<Rc<Signature<'static>>> {
+    let method = match name {
+        "len" => Signature::built_in(name, vec![], 0, Type::Int),
+        "pop" => Signature::built_in(name, vec![], 0, Type::Any),
+        "push" => Signature::built_in(name, vec![Type::Any], 0, Type::Nil),
+        _ => return None,
+    };
+
+    Some(method)
+}
@@ -1,17 +1,26 @@
 use object::{Fields, LoxBoundMethod};
 
 use crate::{
-    heap::{Gc, Heap, LoxStr, Obj},
+    bigint::BigInt,
+    compiler::Compiler,
+    heap::{Gc, GreyStack, Heap, LoxStr, Obj},
     interpreter::{InterpreterResult, VmInit},
-    native::{ClockNative, LoxNativeFun, ValueToStrConverter},
-    object::{self, FunctionType, LoxClass, LoxClosure, LoxFun, LoxInstance, Upvalue},
-    opcodes::{ArgCount, Chunk, ChunkIterator, ConstantIndex, Instruction, Number, Value},
+    native::{
+        ClassOfNative, ClockNative, DeleteFieldNative, FieldsNative, FloatConverter, GetFieldNative, HasFieldNative,
+        IntConverter, LoxNativeFun, MethodsNative, RespondsToNative, SetFieldNative, ValueToStrConverter,
+    },
+    object::{self, FunctionType, CoroutineKind, CoroutineState, EnumVariant, LoxClass, LoxClosure, LoxCoroutine, LoxFun, LoxInstance, LoxList, LoxModule, Upvalue},
+    opcodes::{ArgCount, ByteCodeOffset, Chunk, ChunkIterator, ConstantIndex, Instruction, Int, Number, Value},
 };
-use std::{collections::HashMap, convert::{TryFrom, TryInto}, iter::Peekable, mem, ops::{Add, Div, Mul, Sub}};
+use std::{cmp::Ordering, collections::HashMap, convert::TryInto, fs, iter::Peekable, mem, ops::{Add, Div, Mul, Sub}, path::PathBuf, ptr::NonNull};
 use std::{time, todo};
 
 const FRAMES_MIN_SIZE: usize = 64;
 const STACK_MIN_SIZE: usize = FRAMES_MIN_SIZE * (StackIndex::MAX as usize + 1);
+/// Free stack space guaranteed to a new call frame, covering its locals and temporaries.
+const FRAME_STACK_SIZE: usize = 2 * (StackIndex::MAX as usize + 1);
+
+const PRELUDE: &str = include_str!("prelude.lox");
 
 pub type StackIndex = u8;
 pub type FrameIndex = usize;
@@ -24,10 +33,78 @@ pub struct Vm {
     heap: Heap,
     pub stack: Stack,
     pub call_frames: Vec<CallFrame>,
+    /// Natives and the prelude's definitions, visible from every module.
     pub globals: Globals,
+    /// Every module that has been imported, keyed by its canonical path.
+    pub modules: HashMap<PathBuf, Gc<LoxModule>>,
+    /// Modules whose top level code is still running, with the call frame depth it runs at.
+    loading_modules: Vec<(Gc<LoxModule>, usize)>,
     had_runtime_error: bool,
     pub open_upvalues: Vec<Gc<Upvalue>>,
+    /// Generators and fibers that are currently running, innermost last.
+    pub running_coroutines: Vec<Gc<LoxCoroutine>>,
     pub class_init_method: Gc<LoxStr>,
+    pub operator_methods: OperatorMethods,
+    /// The names of the methods for-in loops call on instances.
+    pub iterator_method: Gc<LoxStr>,
+    pub next_method: Gc<LoxStr>,
+    /// The properties enum variants and enums have without declaring them.
+    pub variant_name_property: Gc<LoxStr>,
+    pub variant_ordinal_property: Gc<LoxStr>,
+    pub enum_variants_property: Gc<LoxStr>,
+    /// The prelude's `Error` class, used for errors raised by the vm itself.
+    /// This is only `None` while the prelude is being run.
+    pub error_class: Option<Gc<LoxClass>>,
+    /// The prelude's `Fiber` class. Calling it creates a fiber rather than an instance.
+    pub fiber_class: Option<Gc<LoxClass>>,
+    pub error_message_field: Gc<LoxStr>,
+    pub error_trace_field: Gc<LoxStr>,
+    /// The script's test blocks, which are only collected when running tests.
+    pub tests: Option<Vec<Gc<LoxClosure>>>,
+    /// While a test block runs, uncaught errors are kept in `test_failure` for the test
+    /// runner to report rather than being printed.
+    running_test: bool,
+    test_failure: Option<String>,
+}
+
+/// Resumes execution at the handler a thrown value was unwound to, or stops the vm
+/// if it was left uncaught.
+macro_rules! resume_after_throw {
+    ($self: ident, $call_frame: ident) => {{
+        if $self.had_runtime_error {
+            return InterpreterResult::RuntimeError;
+        }
+
+        $call_frame = get_callframe(&mut $self.call_frames);
+        continue;
+    }};
+}
+
+/// Calls the operator method `$name` when the operand below the top `$arg_count` values
+/// is an instance that defines it. The method's result replaces the operands once it returns.
+macro_rules! call_operator_method {
+    ($self: ident, $call_frame: ident, $name: expr, $arg_count: expr) => {{
+        if let Some(method) = $self.operator_method($name, $arg_count) {
+            if !$self.call(method, $arg_count) {
+                resume_after_throw!($self, $call_frame);
+            }
+
+            $call_frame = get_callframe(&mut $self.call_frames);
+            continue;
+        }
+    }};
+}
+
+/// Like `call_operator_method!` for the right operand of a binary operator, which is
+/// swapped with the left one so it becomes the receiver.
+macro_rules! call_reflected_operator_method {
+    ($self: ident, $call_frame: ident, $name: expr) => {{
+        if $self.operator_method($name, 0).is_some() {
+            let len = $self.stack.len();
+            $self.stack.swap(len - 1, len - 2);
+            call_operator_method!($self, $call_frame, $name, 1);
+        }
+    }};
 }
 
 impl Vm {
@@ -35,37 +112,92 @@ impl Vm {
         // https://stackoverflow.com/questions/43952104/how-can-i-store-a-chars-iterator-in-the-same-struct-as-the-string-it-is-iteratin
         // https://stackoverflow.com/questions/32300132/why-cant-i-store-a-value-and-a-reference-to-that-value-in-the-same-struct
         // This should be safe since we will not move any Chunks away while using instr_iter.
-        let VmInit { function, heap } = vm_init;
+        let VmInit { function, heap, path } = vm_init;
         let mut globals = HashMap::new();
 
+        // The script function stays on the stack while the prelude runs so the GC can find it.
         let mut stack = Vec::with_capacity(STACK_MIN_SIZE);
         stack.push(Value::Function(function));
 
-        let closure_ptr = heap.manage(LoxClosure::new(function));
-        stack.pop();
-        stack.push(Value::Closure(closure_ptr));
-
         initialize_built_ins(&heap, &mut globals);
 
-        let instr_iter = get_cursor(function.chunk.instr_iter());
-
-        let mut call_frames = Vec::with_capacity(FRAMES_MIN_SIZE);
-        call_frames.push(CallFrame {
-            closure: closure_ptr,
-            ip: instr_iter,
-            frame_index: 0,
-        });
-
         let class_init_method = heap.intern_string("init");
-
-        Vm {
+        let operator_methods = OperatorMethods::new(&heap);
+        let iterator_method = heap.intern_string("iterator");
+        let next_method = heap.intern_string("next");
+        let variant_name_property = heap.intern_string("name");
+        let variant_ordinal_property = heap.intern_string("ordinal");
+        let enum_variants_property = heap.intern_string("variants");
+        let error_message_field = heap.intern_string("message");
+        let error_trace_field = heap.intern_string("trace");
+
+        let mut vm = Vm {
             heap,
             stack,
-            call_frames,
+            call_frames: Vec::with_capacity(FRAMES_MIN_SIZE),
             globals,
+            modules: HashMap::new(),
+            loading_modules: Vec::new(),
             had_runtime_error: false,
             open_upvalues: Vec::new(),
+            running_coroutines: Vec::new(),
             class_init_method,
+            operator_methods,
+            iterator_method,
+            next_method,
+            variant_name_property,
+            variant_ordinal_property,
+            enum_variants_property,
+            error_class: None,
+            fiber_class: None,
+            error_message_field,
+            error_trace_field,
+            tests: None,
+            running_test: false,
+            test_failure: None,
+        };
+
+        vm.load_prelude();
+
+        let module_name = vm.heap.intern_string(path.to_string_lossy());
+        let module = vm.heap.manage(LoxModule::new(module_name, path.clone()));
+        if let Ok(path) = fs::canonicalize(&path) {
+            // Lets an imported module that tries to import the main script report the cycle.
+            vm.modules.insert(path, module);
+        }
+
+        let closure_ptr = vm.heap.manage(LoxClosure::new(function, module));
+        vm.stack.pop();
+        vm.stack.push(Value::Closure(closure_ptr));
+        vm.call_frames.push(CallFrame::new(closure_ptr, 0));
+
+        vm
+    }
+
+    fn load_prelude(&mut self) {
+        let function = Compiler::new(PRELUDE, &self.heap)
+            .compile()
+            .expect("Failed to compile the prelude.");
+
+        let module_name = self.heap.intern_string("prelude");
+        let module = self.heap.manage(LoxModule::new(module_name, PathBuf::new()));
+        let closure_ptr = self.heap.manage(LoxClosure::new(function, module));
+
+        let frame_index = self.stack.len();
+        self.stack.push(Value::Closure(closure_ptr));
+        self.call_frames.push(CallFrame::new(closure_ptr, frame_index));
+
+        if let InterpreterResult::Ok = self.run() {
+            // The prelude's definitions are shared by every module.
+            self.globals.extend(module.globals.iter());
+
+            let error_name = self.heap.intern_string("Error");
+            self.error_class = Some(self.globals[&error_name].unwrap_class());
+
+            let fiber_name = self.heap.intern_string("Fiber");
+            self.fiber_class = Some(self.globals[&fiber_name].unwrap_class());
+        } else {
+            panic!("Failed to run the prelude.");
         }
     }
 
@@ -88,6 +220,21 @@ impl Vm {
             let instr = *instr;
             let index = *index;
 
+            // A getter invoked like a method has returned, so its result is called with the
+            // arguments in place of the method.
+            if let Some(arg_count) = call_frame.getter_call.take() {
+                let callee = self.stack.pop().unwrap();
+                let len = self.stack.len();
+                self.stack[len - 1 - arg_count as usize] = callee;
+
+                if !self.call_value(callee, arg_count) {
+                    resume_after_throw!(self, call_frame);
+                }
+
+                call_frame = get_callframe(&mut self.call_frames);
+                continue;
+            }
+
             #[cfg(feature = "lox_debug")]
             {
                 println!(
@@ -101,33 +248,34 @@ impl Vm {
             match instr {
                 Instruction::Return => {
                     let result = self.stack.pop().unwrap();
-                    let result_slot = call_frame.frame_index;
-
-                    drop(call_frame);
-                    self.call_frames.pop();
 
-                    if self.call_frames.len() == 0 {
-                        self.stack.pop();
+                    if self.return_value(result) {
                         return InterpreterResult::Ok;
                     }
 
-                    self.close_upvalues(result_slot);
-                    self.stack.truncate(result_slot);
-                    self.stack.push(result);
-
                     call_frame = get_callframe(&mut self.call_frames);
+                    continue;
                 }
                 Instruction::LoadConstant(cin) => {
                     let constant = call_frame.get_value(cin);
                     self.stack.push(constant.clone());
                 }
                 Instruction::Negate => {
-                    if let Value::Number(head) = self.stack.last_mut().unwrap() {
-                        *head = -*head;
-                    } else {
-                        self.runtime_error("Operand must be a number.");
-                        return InterpreterResult::RuntimeError;
-                    }
+                    call_operator_method!(self, call_frame, self.operator_methods.negate, 0);
+
+                    let negated = match *self.peek(0) {
+                        Value::Number(head) => Value::Number(-head),
+                        Value::Int(head) => match head.checked_neg() {
+                            Some(negated) => Value::Int(negated),
+                            None => self.big_int_value(BigInt::from_int(head).neg()),
+                        },
+                        Value::BigInt(head) => self.big_int_value(head.neg()),
+                        _ => {
+                            self.runtime_error("Operand must be a number.");
+                            resume_after_throw!(self, call_frame);
+                        }
+                    };
+                    *self.stack.last_mut().unwrap() = negated;
                 }
                 Instruction::Not => {
                     let head = self.stack.last().unwrap();
@@ -136,6 +284,9 @@ impl Vm {
                     self.stack.push(Value::Boolean(not));
                 }
                 Instruction::Equal => {
+                    call_operator_method!(self, call_frame, self.operator_methods.equal, 1);
+                    call_reflected_operator_method!(self, call_frame, self.operator_methods.equal);
+
                     let rhs = self.stack.peek(0);
                     let lhs = self.stack.peek(1);
                     let res = check_equals(lhs, rhs);
@@ -143,23 +294,119 @@ impl Vm {
                     self.stack.pop();
                     self.stack.push(Value::Boolean(res));
                 }
+                Instruction::Is => {
+                    let rhs = self.stack.pop().unwrap();
+                    let lhs = self.stack.pop().unwrap();
+                    self.stack.push(Value::Boolean(is_identical(&lhs, &rhs)));
+                }
+                Instruction::IsInstance => {
+                    let class = match self.stack.pop().unwrap() {
+                        Value::Class(class) => class,
+                        _ => {
+                            self.runtime_error("Class pattern must be a class.");
+                            resume_after_throw!(self, call_frame);
+                        }
+                    };
+
+                    let is_instance = match self.stack.pop().unwrap() {
+                        Value::Instance(instance) => instance.class.is_subclass_of(class),
+                        _ => false,
+                    };
+                    self.stack.push(Value::Boolean(is_instance));
+                }
+                Instruction::IsVariant(field_count) => {
+                    // Unit variants are instances, so a pattern with fields can name them too.
+                    let class = match self.stack.pop().unwrap() {
+                        Value::Class(class) if class.variant.is_some() => class,
+                        Value::Instance(instance) if instance.class.variant.is_some() => instance.class,
+                        _ => {
+                            self.runtime_error("Variant pattern must be an enum variant.");
+                            resume_after_throw!(self, call_frame);
+                        }
+                    };
+
+                    let variant_field_count = class.variant.as_ref().unwrap().fields.len();
+                    if variant_field_count != field_count as usize {
+                        self.runtime_error(format!(
+                            "Pattern has {} fields but {}.{} has {}.",
+                            field_count,
+                            class.superclass.unwrap().name(),
+                            class.name(),
+                            variant_field_count
+                        ));
+                        resume_after_throw!(self, call_frame);
+                    }
+
+                    let is_instance = match self.stack.pop().unwrap() {
+                        Value::Instance(instance) => instance.class.is_subclass_of(class),
+                        _ => false,
+                    };
+                    self.stack.push(Value::Boolean(is_instance));
+                }
+                Instruction::GetVariantField(field) => {
+                    let instance = self.stack.pop().unwrap().unwrap_instance();
+                    let field_name = instance
+                        .class
+                        .variant
+                        .as_ref()
+                        .and_then(|variant| variant.fields.get(field as usize))
+                        .copied();
+
+                    match field_name {
+                        Some(field_name) => {
+                            let value = instance.fields.get(&field_name).copied();
+                            self.stack.push(value.unwrap_or(Value::Nil));
+                        }
+                        None => {
+                            self.runtime_error(format!("Enum variant has no field at position {}.", field));
+                            resume_after_throw!(self, call_frame);
+                        }
+                    }
+                }
                 Instruction::Greater => {
-                    self.perform_binary_op(|a: Number, b: Number| a > b);
+                    // a > b is the same as b < a.
+                    call_operator_method!(self, call_frame, self.operator_methods.greater, 1);
+                    call_reflected_operator_method!(self, call_frame, self.operator_methods.less);
+
+                    if !self.perform_comparison(Ordering::is_gt) {
+                        resume_after_throw!(self, call_frame);
+                    }
                 }
                 Instruction::Less => {
-                    self.perform_binary_op(|a: Number, b: Number| a < b);
+                    call_operator_method!(self, call_frame, self.operator_methods.less, 1);
+                    call_reflected_operator_method!(self, call_frame, self.operator_methods.greater);
+
+                    if !self.perform_comparison(Ordering::is_lt) {
+                        resume_after_throw!(self, call_frame);
+                    }
                 }
                 Instruction::Add => {
-                    self.perform_binary_op_plus();
+                    call_operator_method!(self, call_frame, self.operator_methods.add, 1);
+
+                    if !self.perform_binary_op_plus() {
+                        resume_after_throw!(self, call_frame);
+                    }
                 }
                 Instruction::Subtract => {
-                    self.perform_binary_op(Number::sub);
+                    call_operator_method!(self, call_frame, self.operator_methods.subtract, 1);
+
+                    if !self.perform_arithmetic(&SUBTRACT) {
+                        resume_after_throw!(self, call_frame);
+                    }
                 }
                 Instruction::Multiply => {
-                    self.perform_binary_op(Number::mul);
+                    call_operator_method!(self, call_frame, self.operator_methods.multiply, 1);
+
+                    if !self.perform_arithmetic(&MULTIPLY) {
+                        resume_after_throw!(self, call_frame);
+                    }
                 }
                 Instruction::Divide => {
-                    self.perform_binary_op(Number::div);
+                    call_operator_method!(self, call_frame, self.operator_methods.divide, 1);
+
+                    if !self.perform_arithmetic(&DIVIDE) {
+                        resume_after_throw!(self, call_frame);
+                    }
                 }
                 Instruction::Nil => self.stack.push(Value::Nil),
                 Instruction::True => self.stack.push(Value::Boolean(true)),
@@ -171,26 +418,63 @@ impl Vm {
                 Instruction::Pop => {
                     self.stack.pop();
                 }
-                Instruction::DefineGlobal(var_index) => {
+                Instruction::Dup => {
+                    self.stack.push(*self.peek(0));
+                }
+                Instruction::Swap => {
+                    let len = self.stack.len();
+                    self.stack.swap(len - 1, len - 2);
+                }
+                Instruction::Over => {
+                    self.stack.push(*self.peek(1));
+                }
+                Instruction::DefineGlobal(var_index) | Instruction::DefineConstGlobal(var_index) => {
                     let var_name: Gc<LoxStr> = call_frame.get_value(var_index).try_into().unwrap();
+                    let mut module = call_frame.closure.module;
+
+                    if module.constants.contains(&var_name) {
+                        self.runtime_error(format!("Can't redefine constant '{}'.", var_name));
+                        resume_after_throw!(self, call_frame);
+                    }
+
                     let value = self.stack.pop().unwrap();
-                    self.globals.insert(var_name, value);
+                    module.globals.insert(var_name, value);
+                    if let Instruction::DefineConstGlobal(_) = instr {
+                        module.constants.insert(var_name);
+                    }
                 }
                 Instruction::SetGlobal(var_index) => {
                     let var_name: Gc<LoxStr> = call_frame.get_value(var_index).try_into().unwrap();
                     let value = self.stack.peek(0).clone();
-                    if let None = self.globals.insert(var_name.clone(), value) {
-                        self.globals.remove(&var_name);
+                    let mut module = call_frame.closure.module;
+
+                    if module.constants.contains(&var_name) {
+                        self.runtime_error(format!("Can't assign to constant '{}'.", var_name));
+                        resume_after_throw!(self, call_frame);
+                    }
+
+                    // Assigning to a built in shadows it within the current module.
+                    if module.globals.contains_key(&var_name) || self.globals.contains_key(&var_name) {
+                        module.globals.insert(var_name, value);
+                    } else {
                         self.runtime_error(format!("Undefined variable '{}'.", var_name));
-                        return InterpreterResult::RuntimeError;
+                        resume_after_throw!(self, call_frame);
                     }
                 }
                 Instruction::GetGlobal(var_index) => {
                     let var_name: Gc<LoxStr> = call_frame.get_value(var_index).try_into().unwrap();
-                    if let Some(value) = self.globals.get(&var_name) {
+                    let value = call_frame
+                        .closure
+                        .module
+                        .globals
+                        .get(&var_name)
+                        .or_else(|| self.globals.get(&var_name));
+
+                    if let Some(value) = value {
                         self.stack.push(value.clone());
                     } else {
                         self.runtime_error(format!("Undefined variable '{}'.", var_name));
+                        resume_after_throw!(self, call_frame);
                     }
                 }
                 Instruction::GetLocal(var_index) => {
@@ -211,21 +495,173 @@ impl Vm {
                         continue;
                     }
                 }
+                Instruction::JumpIfNil(offset) => {
+                    if let Value::Nil = self.stack.peek(0) {
+                        let jump_index = index + offset as usize;
+                        call_frame.ip = get_cursor(call_frame.get_chunk().instr_iter_jump(jump_index));
+                        continue;
+                    }
+                }
                 Instruction::JumpForward(offset) => {
                     let jump_index = index + offset as usize;
                     call_frame.ip = get_cursor(call_frame.get_chunk().instr_iter_jump(jump_index));
                     continue;
                 }
+                Instruction::JumpIfArgPassed(offset, param_index) => {
+                    if call_frame.arg_count > param_index {
+                        let jump_index = index + offset as usize;
+                        call_frame.ip = get_cursor(call_frame.get_chunk().instr_iter_jump(jump_index));
+                        continue;
+                    }
+                }
+                Instruction::Enum(name_in) => {
+                    let enum_name = call_frame.get_value(name_in).unwrap_string();
+                    let mut new_enum = LoxClass::new(enum_name);
+                    new_enum.is_enum = true;
+
+                    let new_enum = self.heap.manage_gc(new_enum, self);
+                    self.stack.push(Value::Class(new_enum));
+                }
+                Instruction::EnumVariant(name_in, field_count) => {
+                    let variant_name = call_frame.get_value(name_in).unwrap_string();
+                    let fields = self
+                        .stack
+                        .split_off(self.stack.len() - field_count as usize)
+                        .into_iter()
+                        .map(|field| field.unwrap_string())
+                        .collect();
+
+                    let mut enum_class = self.peek(0).unwrap_class();
+                    if enum_class.variant(variant_name).is_some() {
+                        self.runtime_error(format!("Enum variant '{}' is already defined.", variant_name));
+                        resume_after_throw!(self, call_frame);
+                    }
+
+                    // Each variant is a subclass of the enum, so variants match the enum as a class pattern.
+                    let mut variant_class = LoxClass::new(variant_name);
+                    variant_class.superclass = Some(enum_class);
+                    variant_class.variant = Some(EnumVariant {
+                        ordinal: enum_class.variants.len(),
+                        fields,
+                    });
+                    let variant_class = self.heap.manage_gc(variant_class, self);
+
+                    let variant = if variant_class.variant.as_ref().unwrap().fields.is_empty() {
+                        self.stack.push(Value::Class(variant_class));
+                        let instance = self.heap.manage_gc(LoxInstance::new(variant_class), self);
+                        self.stack.pop();
+                        Value::Instance(instance)
+                    } else {
+                        Value::Class(variant_class)
+                    };
+
+                    self.heap.update_allocation(
+                        enum_class,
+                        move || enum_class.variants.push((variant_name, variant)),
+                        self,
+                    );
+                }
+                Instruction::BuildList(item_count) => {
+                    let mut list = self.heap.manage_gc(LoxList::new(), self);
+                    let mut items = self.stack.split_off(self.stack.len() - item_count as usize);
+
+                    self.stack.push(Value::List(list));
+                    self.heap.update_allocation(list, || list.items.append(&mut items), self);
+                }
+                Instruction::UnpackList(count) | Instruction::UnpackListRest(count) => {
+                    let list = match *self.peek(0) {
+                        Value::List(list) => list,
+                        _ => {
+                            self.runtime_error("Can only destructure lists with a list pattern.");
+                            resume_after_throw!(self, call_frame);
+                        }
+                    };
+
+                    // The rest is allocated while the list it comes from is still on the stack.
+                    let rest = if let Instruction::UnpackListRest(_) = instr {
+                        let mut rest = LoxList::new();
+                        rest.items = list.items.iter().skip(count as usize).copied().collect();
+                        Some(self.heap.manage_gc(rest, self))
+                    } else {
+                        None
+                    };
+
+                    self.stack.pop();
+                    for i in 0..count as usize {
+                        self.stack.push(list.items.get(i).copied().unwrap_or(Value::Nil));
+                    }
+
+                    if let Some(rest) = rest {
+                        self.stack.push(Value::List(rest));
+                    }
+                }
+                Instruction::UnpackFields(count) => {
+                    let names = self.stack.split_off(self.stack.len() - count as usize);
+                    let instance = match self.stack.pop().unwrap() {
+                        Value::Instance(instance) => instance,
+                        _ => {
+                            self.runtime_error("Can only destructure instances with a field pattern.");
+                            resume_after_throw!(self, call_frame);
+                        }
+                    };
+
+                    for name in names.iter() {
+                        let value = instance.fields.get(&name.unwrap_string()).copied();
+                        self.stack.push(value.unwrap_or(Value::Nil));
+                    }
+                }
+                Instruction::GetIndex => {
+                    call_operator_method!(self, call_frame, self.operator_methods.index, 1);
+
+                    let index_value = *self.peek(0);
+                    let target = *self.peek(1);
+
+                    let value = match target {
+                        Value::List(list) => match self.list_index(list, index_value) {
+                            Some(i) => list.items[i],
+                            None => resume_after_throw!(self, call_frame),
+                        },
+                        _ => {
+                            self.runtime_error("Only lists can be indexed.");
+                            resume_after_throw!(self, call_frame);
+                        }
+                    };
+
+                    self.stack.pop();
+                    self.stack.pop();
+                    self.stack.push(value);
+                }
+                Instruction::SetIndex => {
+                    call_operator_method!(self, call_frame, self.operator_methods.set_index, 2);
+
+                    let value = *self.peek(0);
+                    let index_value = *self.peek(1);
+                    let target = *self.peek(2);
+
+                    match target {
+                        Value::List(mut list) => match self.list_index(list, index_value) {
+                            Some(i) => list.items[i] = value,
+                            None => resume_after_throw!(self, call_frame),
+                        },
+                        _ => {
+                            self.runtime_error("Only lists can be indexed.");
+                            resume_after_throw!(self, call_frame);
+                        }
+                    }
+
+                    let len = self.stack.len();
+                    self.stack.truncate(len - 3);
+                    self.stack.push(value);
+                }
                 Instruction::JumpBack(offset) => {
                     let jump_index = index - offset as usize;
                     call_frame.ip = get_cursor(call_frame.get_chunk().instr_iter_jump(jump_index));
                     continue;
                 }
                 Instruction::Call(arg_count) => {
-                    drop(call_frame);
                     let callee = *self.peek(arg_count as usize);
                     if !self.call_value(callee, arg_count) {
-                        return InterpreterResult::RuntimeError;
+                        resume_after_throw!(self, call_frame);
                     }
 
                     call_frame = get_callframe(&mut self.call_frames);
@@ -233,8 +669,9 @@ impl Vm {
                 }
                 Instruction::Closure(func_index) => {
                     if let Value::Function(function) = call_frame.get_value(func_index) {
+                        let module = call_frame.closure.module;
                         let mut closure =
-                            self.heap.manage_gc(LoxClosure::new(function.clone()), self);
+                            self.heap.manage_gc(LoxClosure::new(function.clone(), module), self);
 
                         // We push the closure here early since we will be allocating upvalues down the line
                         // which may trigger GC and Deallocate the closure.
@@ -288,16 +725,60 @@ impl Vm {
                             let field_val = *field_val;
                             self.stack.pop();
                             self.stack.push(field_val);
+                        } else if let Some(value) = self.variant_property(*instance, prop_name) {
+                            self.stack.pop();
+                            self.stack.push(value);
+                        } else if let Some(getter) = instance.class.getters.get(&prop_name) {
+                            // The instance on top of the stack becomes the getter's receiver.
+                            let getter = getter.unwrap_closure();
+
+                            if !self.call(getter, 0) {
+                                resume_after_throw!(self, call_frame);
+                            }
+
+                            call_frame = get_callframe(&mut self.call_frames);
+                            continue;
                         } else {
                             let class = instance.class;
 
                             if !self.bind_method(class, prop_name) {
-                                return InterpreterResult::RuntimeError;
+                                resume_after_throw!(self, call_frame);
                             }
                         }
+                    } else if let Value::Class(class) = instance_value {
+                        if let Some(method) = class.static_methods.get(&prop_name) {
+                            let closure = method.unwrap_closure();
+                            let class_value = *instance_value;
+                            let bound_method =
+                                self.heap.manage_gc(LoxBoundMethod::new(closure, class_value), self);
+
+                            self.stack.pop();
+                            self.stack.push(Value::BoundMethod(bound_method));
+                        } else if let Some(variant) = class.variant(prop_name) {
+                            self.stack.pop();
+                            self.stack.push(variant);
+                        } else if prop_name == self.enum_variants_property && class.is_enum {
+                            let class = *class;
+                            self.push_enum_variants(class);
+                        } else {
+                            self.runtime_error(format!("Undefined property '{}'.", prop_name));
+                            resume_after_throw!(self, call_frame);
+                        }
+                    } else if let Value::Module(module) = instance_value {
+                        if let Some(value) = module.globals.get(&prop_name) {
+                            let value = *value;
+                            self.stack.pop();
+                            self.stack.push(value);
+                        } else {
+                            self.runtime_error(format!(
+                                "Undefined name '{}' in module '{}'.",
+                                prop_name, module.name
+                            ));
+                            resume_after_throw!(self, call_frame);
+                        }
                     } else {
                         self.runtime_error("Only instances have properties.");
-                        return InterpreterResult::RuntimeError;
+                        resume_after_throw!(self, call_frame);
                     }
                 }
                 Instruction::SetProperty(prop_in) => {
@@ -307,6 +788,17 @@ impl Vm {
                     let set_value = *self.peek(0);
 
                     if let Value::Instance(mut instance) = instance_value {
+                        // Unit variants are shared and every variant compares by its fields.
+                        if instance.class.variant.is_some() {
+                            self.runtime_error(format!(
+                                "Can't set field '{}' on enum variant {}.{}.",
+                                prop_name,
+                                instance.class.superclass.unwrap().name(),
+                                instance.class.name()
+                            ));
+                            resume_after_throw!(self, call_frame);
+                        }
+
                         self.heap.update_allocation(
                             instance,
                             move || {
@@ -320,45 +812,120 @@ impl Vm {
                         self.stack.push(set_value);
                     } else {
                         self.runtime_error("Only instances have fields.");
-                        return InterpreterResult::RuntimeError;
+                        resume_after_throw!(self, call_frame);
                     }
                 }
                 Instruction::Method(name_in) => {
                     let method_name = call_frame.get_value(name_in).unwrap_string();
-                    self.define_method(method_name);
+                    self.define_method(method_name, |class| &mut class.methods);
+                }
+                Instruction::StaticMethod(name_in) => {
+                    let method_name = call_frame.get_value(name_in).unwrap_string();
+                    self.define_method(method_name, |class| &mut class.static_methods);
+                }
+                Instruction::Getter(name_in) => {
+                    let method_name = call_frame.get_value(name_in).unwrap_string();
+                    self.define_method(method_name, |class| &mut class.getters);
                 }
                 Instruction::Invoke(name_in, arg_count) => {
                     let method_name = call_frame.get_value(name_in).unwrap_string();
                     if !self.invoke(method_name, arg_count) {
-                        return InterpreterResult::RuntimeError;
+                        resume_after_throw!(self, call_frame);
                     }
 
                     call_frame = get_callframe(&mut self.call_frames);
                     continue;
                 }
                 Instruction::Inherit => {
-                    let super_class = if let Value::Class(class) = self.peek(1) {
-                        *class
-                    } else {
-                        self.runtime_error("Superclass must be a class.");
-                        return InterpreterResult::RuntimeError;
+                    let super_class = match self.peek(1) {
+                        Value::Class(class) if class.is_trait => {
+                            self.runtime_error(format!("Can't inherit from trait '{}'. Use it after 'with'.", class.name()));
+                            resume_after_throw!(self, call_frame);
+                        }
+                        Value::Class(class) => *class,
+                        _ => {
+                            self.runtime_error("Superclass must be a class.");
+                            resume_after_throw!(self, call_frame);
+                        }
                     };
 
                     let mut sub_class = self.peek(0).unwrap_class();
+                    sub_class.superclass = Some(super_class);
 
                     for (name, method) in super_class.methods.iter() {
                         sub_class.methods.insert(*name, *method);
                     }
 
+                    for (name, method) in super_class.static_methods.iter() {
+                        sub_class.static_methods.insert(*name, *method);
+                    }
+
+                    for (name, getter) in super_class.getters.iter() {
+                        sub_class.getters.insert(*name, *getter);
+                    }
+
                     // Pop the sub class from the stack but leave the super class.
                     self.stack.pop();
                 }
+                Instruction::Test => {
+                    let test = self.stack.pop().unwrap().unwrap_closure();
+                    if let Some(tests) = &mut self.tests {
+                        tests.push(test);
+                    }
+                }
+                Instruction::AssertFailed(source_in) => {
+                    let source = call_frame.get_value(source_in).unwrap_string();
+                    let message = match self.stack.pop().unwrap() {
+                        Value::Nil => format!("Assertion '{}' failed.", source),
+                        message => format!("Assertion '{}' failed: {}", source, message),
+                    };
+
+                    self.runtime_error(message);
+                    resume_after_throw!(self, call_frame);
+                }
+                Instruction::Trait(name_in) => {
+                    let trait_name = call_frame.get_value(name_in).unwrap_string();
+                    let mut new_trait = LoxClass::new(trait_name);
+                    new_trait.is_trait = true;
+
+                    let new_trait = self.heap.manage_gc(new_trait, self);
+                    self.stack.push(Value::Class(new_trait));
+                }
+                Instruction::UseTrait => {
+                    let used_trait = match self.peek(1) {
+                        Value::Class(class) if class.is_trait => *class,
+                        _ => {
+                            self.runtime_error("Can only use traits after 'with'.");
+                            resume_after_throw!(self, call_frame);
+                        }
+                    };
+
+                    let class = self.peek(0).unwrap_class();
+                    if let Err(message) = self.use_trait(class, used_trait) {
+                        self.runtime_error(message);
+                        resume_after_throw!(self, call_frame);
+                    }
+
+                    self.stack.pop();
+                    self.stack.pop();
+                }
                 Instruction::GetSuper(method_name_in) => {
                     let method_name = call_frame.get_value(method_name_in).unwrap_string();
                     let super_class = self.stack.pop().unwrap().unwrap_class();
 
+                    if let Some(getter) = super_class.getters.get(&method_name) {
+                        let getter = getter.unwrap_closure();
+
+                        if !self.call(getter, 0) {
+                            resume_after_throw!(self, call_frame);
+                        }
+
+                        call_frame = get_callframe(&mut self.call_frames);
+                        continue;
+                    }
+
                     if !self.bind_method(super_class, method_name) {
-                        return InterpreterResult::RuntimeError;
+                        resume_after_throw!(self, call_frame);
                     }
                 }
                 Instruction::SuperInvoke(method_name_in, arg_count) => {
@@ -366,53 +933,409 @@ impl Vm {
                     let super_class = self.stack.pop().unwrap().unwrap_class();
 
                     if !self.invoke_from_class(super_class, method_name, arg_count) {
-                        return InterpreterResult::RuntimeError;
+                        resume_after_throw!(self, call_frame);
+                    }
+
+                    call_frame = get_callframe(&mut self.call_frames);
+                    continue;
+                }
+                Instruction::Throw => {
+                    let exception = self.stack.pop().unwrap();
+
+                    self.throw_value(exception);
+                    resume_after_throw!(self, call_frame);
+                }
+                Instruction::PushHandler(catch_offset, finally_offset) => {
+                    let handler_index = |offset: ByteCodeOffset| {
+                        if offset == 0 {
+                            None
+                        } else {
+                            Some(index + offset as usize)
+                        }
+                    };
+
+                    let handler = Handler {
+                        catch_index: handler_index(catch_offset),
+                        finally_index: handler_index(finally_offset),
+                        stack_len: self.stack.len(),
+                        completions_len: call_frame.completions.len(),
+                    };
+                    call_frame.handlers.push(handler);
+                }
+                Instruction::PopHandler => {
+                    call_frame.handlers.pop();
+                }
+                Instruction::EnterFinally => {
+                    call_frame.completions.push(Completion::Normal);
+                }
+                Instruction::Yield => {
+                    let value = self.stack.pop().unwrap();
+
+                    // The generator picks up after the yield when it's resumed.
+                    call_frame.ip.next();
+                    self.leave_coroutine(CoroutineState::Suspended, value);
+
+                    call_frame = get_callframe(&mut self.call_frames);
+                    continue;
+                }
+                Instruction::IterInit => match *self.peek(0) {
+                    Value::List(_) | Value::String(_) | Value::Generator(_) => {}
+                    Value::Class(class) if class.is_enum => {
+                        // Enums iterate over their variants in declaration order.
+                        self.push_enum_variants(class);
+                    }
+                    Value::Instance(_) => {
+                        // Whatever iterator() returns replaces the instance once the call returns.
+                        if !self.invoke(self.iterator_method, 0) {
+                            resume_after_throw!(self, call_frame);
+                        }
+
+                        call_frame = get_callframe(&mut self.call_frames);
+                        continue;
+                    }
+                    _ => {
+                        self.runtime_error("Can only iterate over lists, strings, generators and instances.");
+                        resume_after_throw!(self, call_frame);
+                    }
+                },
+                Instruction::IterNext(iterator_index) => {
+                    let slot = call_frame.frame_index + iterator_index as usize;
+                    match (self.stack[slot], self.stack[slot + 1]) {
+                        (Value::List(list), Value::Int(position)) => {
+                            // The position is set to nil once the list runs out.
+                            match list.items.get(position as usize) {
+                                Some(item) => {
+                                    self.stack[slot + 1] = Value::Int(position + 1);
+                                    self.stack.push(*item);
+                                }
+                                None => {
+                                    self.stack[slot + 1] = Value::Nil;
+                                    self.stack.push(Value::Nil);
+                                }
+                            }
+                        }
+                        (Value::String(string), Value::Int(position)) => {
+                            // Strings step through characters, tracking the byte position of the next one.
+                            match string.as_str()[position as usize..].chars().next() {
+                                Some(ch) => {
+                                    self.stack[slot + 1] = Value::Int(position + ch.len_utf8() as Int);
+                                    let ch = self.heap.intern_string_gc(ch.to_string(), self);
+                                    self.stack.push(Value::String(ch));
+                                }
+                                None => {
+                                    self.stack[slot + 1] = Value::Nil;
+                                    self.stack.push(Value::Nil);
+                                }
+                            }
+                        }
+                        (iterator @ Value::Generator(_), _) | (iterator @ Value::Instance(_), _) => {
+                            self.stack.push(iterator);
+                            if !self.invoke(self.next_method, 0) {
+                                resume_after_throw!(self, call_frame);
+                            }
+
+                            call_frame = get_callframe(&mut self.call_frames);
+                            continue;
+                        }
+                        (iterator, _) => {
+                            self.runtime_error(format!(
+                                "Iterator must be a list, string, generator or instance, got '{}'.",
+                                iterator
+                            ));
+                            resume_after_throw!(self, call_frame);
+                        }
+                    }
+                }
+                Instruction::JumpIfIterDone(offset, iterator_index) => {
+                    let slot = call_frame.frame_index + iterator_index as usize;
+                    let done = match (self.stack[slot], self.stack[slot + 1]) {
+                        (Value::List(_), state) | (Value::String(_), state) => matches!(state, Value::Nil),
+                        (Value::Generator(generator), _) => generator.state == CoroutineState::Done,
+                        // Instance iterators signal the end by returning nil from next(), so unlike
+                        // lists and generators they can't produce nil as an item.
+                        _ => matches!(self.peek(0), Value::Nil),
+                    };
+
+                    if done {
+                        self.stack.pop();
+                        let jump_index = index + offset as usize;
+                        call_frame.ip = get_cursor(call_frame.get_chunk().instr_iter_jump(jump_index));
+                        continue;
+                    }
+                }
+                Instruction::ImportModule(path_in) => {
+                    let path = call_frame.get_value(path_in).unwrap_string();
+
+                    if !self.import_module(path) {
+                        resume_after_throw!(self, call_frame);
                     }
 
                     call_frame = get_callframe(&mut self.call_frames);
                     continue;
                 }
+                Instruction::EndFinally => match call_frame.completions.pop().unwrap() {
+                    Completion::Normal => {}
+                    Completion::Throw(exception) => {
+                        self.throw_value(exception);
+                        resume_after_throw!(self, call_frame);
+                    }
+                    Completion::Return(result) => {
+                        if self.return_value(result) {
+                            return InterpreterResult::Ok;
+                        }
+
+                        call_frame = get_callframe(&mut self.call_frames);
+                        continue;
+                    }
+                },
             };
             call_frame.ip.next();
-
-            if self.had_runtime_error {
-                return InterpreterResult::RuntimeError;
-            }
         }
 
         return InterpreterResult::Ok;
     }
 
-    fn close_upvalues(&mut self, stack_in: usize) {
-        let value_ptr = &mut self.stack[stack_in] as *mut Value;
-        let mut new_size = self.open_upvalues.len();
-        for (index, ptr) in self.open_upvalues.iter_mut().enumerate().rev() {
-            if ptr.value_ptr() >= value_ptr {
-                ptr.close();
-            } else {
-                new_size = index + 1;
+    /// Pops the current call frame and hands `result` to its caller, running any pending
+    /// finally block first. Returns true once the outermost frame has returned.
+    fn return_value(&mut self, result: Value) -> bool {
+        if self.enter_finally(Completion::Return(result)) {
+            return false;
+        }
+
+        if let Some((mut module, depth)) = self.loading_modules.last().copied() {
+            if depth == self.call_frames.len() {
+                module.loaded = true;
+                self.loading_modules.pop();
             }
         }
 
-        self.open_upvalues.truncate(new_size);
-    }
+        let call_frame = self.call_frames.pop().unwrap();
 
-    fn capture_upvalue(&mut self, value_ptr: *mut Value) -> Gc<Upvalue> {
-        let mut insert_index = 0;
-        for (index, val) in self.open_upvalues.iter().enumerate().rev() {
-            if val.value_ptr() == value_ptr {
-                return *val;
+        if self.call_frames.is_empty() {
+            if !self.running_coroutines.is_empty() {
+                self.close_upvalues(0);
+                self.stack.clear();
+                self.leave_coroutine(CoroutineState::Done, result);
+                return false;
             }
 
-            if val.value_ptr() < value_ptr {
-                insert_index = index + 1;
-                break;
-            }
+            self.stack.pop();
+            return true;
         }
 
-        let upvalue_ptr = self.heap.manage_gc(Upvalue::new(value_ptr), self);
-        self.open_upvalues.insert(insert_index, upvalue_ptr);
-        return upvalue_ptr;
+        self.close_upvalues(call_frame.frame_index);
+        self.stack.truncate(call_frame.frame_index);
+        self.stack.push(result);
+
+        // Step the caller past its Call instruction, unless it still has to call a getter's result.
+        let caller = self.call_frames.last_mut().unwrap();
+        if caller.getter_call.is_none() {
+            caller.ip.next();
+        }
+        false
+    }
+
+    /// Unwinds the current frame's handlers to the innermost one with a finally block
+    /// and enters that block with `completion` pending.
+    fn enter_finally(&mut self, completion: Completion) -> bool {
+        let call_frame = self.call_frames.last_mut().unwrap();
+
+        while let Some(handler) = call_frame.handlers.pop() {
+            if let Some(finally_index) = handler.finally_index {
+                call_frame.completions.truncate(handler.completions_len);
+                call_frame.completions.push(completion);
+                call_frame.ip = get_cursor(call_frame.get_chunk().instr_iter_jump(finally_index));
+
+                self.close_upvalues(handler.stack_len);
+                self.stack.truncate(handler.stack_len);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Unwinds call frames to the innermost active handler and transfers control to it.
+    /// Uncaught values are reported and flag a runtime error.
+    fn throw_value(&mut self, exception: Value) -> bool {
+        let trace = stack_trace(&mut self.call_frames);
+        self.attach_trace(exception, &trace);
+
+        loop {
+            let call_frame = match self.call_frames.last_mut() {
+                Some(call_frame) => call_frame,
+                // A coroutine that throws is finished and the exception continues in its resumer.
+                None if !self.running_coroutines.is_empty() => {
+                    self.close_upvalues(0);
+                    self.stack.clear();
+
+                    let mut coroutine = self.running_coroutines.pop().unwrap();
+                    self.switch_context(&mut coroutine.context);
+                    coroutine.state = CoroutineState::Done;
+                    continue;
+                }
+                None => break,
+            };
+
+            if let Some(mut handler) = call_frame.handlers.pop() {
+                call_frame.completions.truncate(handler.completions_len);
+                call_frame.getter_call = None;
+
+                let catch_index = handler.catch_index.take();
+                let target_index = if let Some(catch_index) = catch_index {
+                    // The handler stays active while the catch block runs so that its
+                    // finally block is still entered if the catch block throws or returns.
+                    if handler.finally_index.is_some() {
+                        call_frame.handlers.push(handler);
+                    }
+
+                    catch_index
+                } else {
+                    call_frame.completions.push(Completion::Throw(exception));
+                    handler.finally_index.unwrap()
+                };
+
+                call_frame.ip = get_cursor(call_frame.get_chunk().instr_iter_jump(target_index));
+
+                self.close_upvalues(handler.stack_len);
+                self.stack.truncate(handler.stack_len);
+
+                // The catch block binds the thrown value as its first local.
+                if catch_index.is_some() {
+                    self.stack.push(exception);
+                }
+
+                return true;
+            }
+
+            self.call_frames.pop();
+
+            // Drop modules whose loading was aborted so a later import runs them afresh.
+            while let Some((module, depth)) = self.loading_modules.last().copied() {
+                if depth <= self.call_frames.len() {
+                    break;
+                }
+
+                self.modules.remove(&module.path);
+                self.loading_modules.pop();
+            }
+        }
+
+        let report = self.uncaught_report(exception, &trace);
+        if self.running_test {
+            self.test_failure = Some(report);
+        } else {
+            eprintln!("{}", report);
+        }
+
+        self.had_runtime_error = true;
+        false
+    }
+
+    /// Records where an `Error` instance was first thrown from.
+    fn attach_trace(&mut self, exception: Value, trace: &str) {
+        let (error_class, mut instance) = match (self.error_class, exception) {
+            (Some(error_class), Value::Instance(instance)) => (error_class, instance),
+            _ => return,
+        };
+
+        if !instance.class.is_subclass_of(error_class)
+            || instance.fields.contains_key(&self.error_trace_field)
+        {
+            return;
+        }
+
+        self.stack.push(exception);
+
+        let trace = self.heap.intern_string_gc(trace, self);
+        let trace_field = self.error_trace_field;
+        self.heap.update_allocation(
+            instance,
+            move || {
+                instance.fields.insert(trace_field, Value::String(trace));
+            },
+            self,
+        );
+
+        self.stack.pop();
+    }
+
+    fn uncaught_report(&self, exception: Value, trace: &str) -> String {
+        let message = match exception {
+            Value::Instance(instance) => instance.fields.get(&self.error_message_field).copied(),
+            _ => None,
+        };
+
+        match message {
+            Some(message) => format!("{}\n{}", message, trace),
+            None => format!("Uncaught exception: {}\n{}", exception, trace),
+        }
+    }
+
+    /// Runs the test blocks collected while the script ran, each on an empty stack, and
+    /// prints whether each one passed. Returns whether they all did.
+    pub fn run_tests(&mut self) -> bool {
+        let tests = self.tests.clone().unwrap_or_default();
+        let mut failed = 0;
+
+        for test in tests.iter() {
+            self.close_upvalues(0);
+            self.stack.clear();
+            self.had_runtime_error = false;
+
+            self.running_test = true;
+            self.stack.push(Value::Closure(*test));
+            self.call_frames.push(CallFrame::new(*test, 0));
+            let result = self.run();
+            self.running_test = false;
+
+            match result {
+                InterpreterResult::Ok => println!("PASS {}", test.function.name),
+                _ => {
+                    failed += 1;
+                    println!("FAIL {}", test.function.name);
+                    for line in self.test_failure.take().unwrap_or_default().lines() {
+                        println!("    {}", line);
+                    }
+                }
+            }
+        }
+
+        println!("{} passed, {} failed", tests.len() - failed, failed);
+        failed == 0
+    }
+
+    fn close_upvalues(&mut self, stack_in: usize) {
+        let value_ptr = self.stack.as_mut_ptr().wrapping_add(stack_in);
+        let mut new_size = self.open_upvalues.len();
+        for (index, ptr) in self.open_upvalues.iter_mut().enumerate().rev() {
+            if ptr.value_ptr() >= value_ptr {
+                ptr.close();
+            } else {
+                new_size = index + 1;
+            }
+        }
+
+        self.open_upvalues.truncate(new_size);
+    }
+
+    fn capture_upvalue(&mut self, value_ptr: *mut Value) -> Gc<Upvalue> {
+        let mut insert_index = 0;
+        for (index, val) in self.open_upvalues.iter().enumerate().rev() {
+            if val.value_ptr() == value_ptr {
+                return *val;
+            }
+
+            if val.value_ptr() < value_ptr {
+                insert_index = index + 1;
+                break;
+            }
+        }
+
+        let owner = self.running_coroutines.last().copied();
+        let upvalue_ptr = self.heap.manage_gc(Upvalue::new(value_ptr, owner), self);
+        self.open_upvalues.insert(insert_index, upvalue_ptr);
+        return upvalue_ptr;
     }
 
     fn call_value(&mut self, callee: Value, arg_count: ArgCount) -> bool {
@@ -421,7 +1344,13 @@ impl Vm {
             Value::NativeFunction(mut fun_ptr) => {
                 let frame_index = self.stack.len() - arg_count as usize;
                 let stack_window = &self.stack[frame_index..];
-                let res = fun_ptr.callable.call(arg_count, stack_window, &self.heap);
+                let res = match fun_ptr.callable.call(arg_count, stack_window, &self.heap) {
+                    Ok(res) => res,
+                    Err(message) => {
+                        self.runtime_error(message);
+                        return false;
+                    }
+                };
                 self.stack.truncate(frame_index - 1);
                 self.stack.push(res);
 
@@ -429,6 +1358,16 @@ impl Vm {
                 self.call_frames.last_mut().unwrap().ip.next();
                 true
             }
+            Value::Class(class) if Some(class) == self.fiber_class => self.create_fiber(arg_count),
+            Value::Class(class) if class.variant.is_some() => self.construct_variant(class, arg_count),
+            Value::Class(class) if class.is_trait => {
+                self.runtime_error(format!("Can't instantiate trait '{}'.", class.name()));
+                false
+            }
+            Value::Class(class) if class.is_enum => {
+                self.runtime_error(format!("Can't instantiate enum '{}'. Use one of its variants.", class.name()));
+                false
+            }
             Value::Class(class) => {
                 let instance = self.heap.manage_gc(LoxInstance::new(class), self);
 
@@ -462,36 +1401,189 @@ impl Vm {
         }
     }
 
-    fn call(&mut self, closure_ptr: Gc<LoxClosure>, arg_count: ArgCount) -> bool {
-        if arg_count as i32 != closure_ptr.function.arity {
+    /// Copies a trait's methods into a class. Traits are used before any of the class's own
+    /// methods are defined, so a method that isn't inherited came from an earlier trait and
+    /// having both is a conflict. The class's own methods still override trait methods.
+    fn use_trait(&mut self, mut class: Gc<LoxClass>, mut used_trait: Gc<LoxClass>) -> Result<(), String> {
+        let tables: [fn(&mut LoxClass) -> &mut Fields; 3] =
+            [|class| &mut class.methods, |class| &mut class.static_methods, |class| &mut class.getters];
+        let trait_name = used_trait.name();
+
+        for table in tables.iter() {
+            for (name, method) in table(&mut used_trait).iter() {
+                let existing = match table(&mut class).get(name) {
+                    Some(existing) => *existing,
+                    None => continue,
+                };
+
+                let inherited = class
+                    .superclass
+                    .and_then(|mut superclass| table(&mut superclass).get(name).copied())
+                    .map_or(false, |inherited| is_identical(&existing, &inherited));
+
+                // Using two traits that got a method from the same trait is fine.
+                if !inherited && !is_identical(&existing, method) {
+                    return Err(format!(
+                        "Method '{}' from trait '{}' conflicts with a method from another trait of '{}'.",
+                        name,
+                        trait_name,
+                        class.name()
+                    ));
+                }
+            }
+        }
+
+        self.heap.update_allocation(
+            class,
+            || {
+                for table in tables.iter() {
+                    for (name, method) in table(&mut used_trait).iter() {
+                        table(&mut class).insert(*name, *method);
+                    }
+                }
+                class.traits.push(used_trait);
+            },
+            self,
+        );
+
+        Ok(())
+    }
+
+    /// Creates an instance of an enum variant that carries values, taking them from the arguments.
+    fn construct_variant(&mut self, class: Gc<LoxClass>, arg_count: ArgCount) -> bool {
+        let variant = class.variant.as_ref().unwrap();
+        if arg_count as usize != variant.fields.len() {
             self.runtime_error(format!(
                 "Expected {} arguments but got {}.",
-                closure_ptr.function.arity, arg_count
+                variant.fields.len(),
+                arg_count
             ));
             return false;
         }
-        let cursor = get_cursor(closure_ptr.function.chunk.instr_iter());
-        let call_frame = CallFrame {
-            closure: closure_ptr,
-            ip: cursor,
-            frame_index: self.stack.len() - arg_count as usize - 1,
-        };
+
+        let mut instance = self.heap.manage_gc(LoxInstance::new(class), self);
+        let args = self.stack.split_off(self.stack.len() - arg_count as usize);
+        self.stack.pop();
+        self.stack.push(Value::Instance(instance));
+
+        self.heap.update_allocation(
+            instance,
+            || {
+                for (field, value) in variant.fields.iter().zip(args.iter()) {
+                    instance.fields.insert(*field, *value);
+                }
+            },
+            self,
+        );
+
+        // Since we skip ip.next after calls we need to add call ip.next for native calls ourselves.
+        self.call_frames.last_mut().unwrap().ip.next();
+        true
+    }
+
+    /// Reads the `name` and `ordinal` every enum variant has.
+    fn variant_property(&self, instance: Gc<LoxInstance>, prop_name: Gc<LoxStr>) -> Option<Value> {
+        let variant = instance.class.variant.as_ref()?;
+
+        if prop_name == self.variant_name_property {
+            Some(Value::String(instance.class.name()))
+        } else if prop_name == self.variant_ordinal_property {
+            Some(Value::Int(variant.ordinal as Int))
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the enum on top of the stack with a list of its variants.
+    fn push_enum_variants(&mut self, class: Gc<LoxClass>) {
+        let mut list = self.heap.manage_gc(LoxList::new(), self);
+        *self.stack.last_mut().unwrap() = Value::List(list);
+
+        self.heap.update_allocation(
+            list,
+            || list.items.extend(class.variants.iter().map(|(_, variant)| *variant)),
+            self,
+        );
+    }
+
+    fn call(&mut self, closure_ptr: Gc<LoxClosure>, arg_count: ArgCount) -> bool {
+        let function = &closure_ptr.function;
+        if !function.accepts(arg_count as i32) {
+            self.runtime_error(function.arity_error(arg_count as i32));
+            return false;
+        }
 
         if self.call_frames.len() == FRAMES_MIN_SIZE {
             self.runtime_error("Stack overflow.");
             return false;
         }
+
+        self.reserve_frame_stack();
+
+        // Omitted arguments are filled in by the function's prologue.
+        let max_arity = function.max_arity() as usize;
+        for _ in arg_count as usize..max_arity {
+            self.stack.push(Value::Nil);
+        }
+
+        let mut slot_count = max_arity;
+        if function.has_rest {
+            let extra_count = (arg_count as usize).saturating_sub(max_arity);
+            let mut rest = self.heap.manage_gc(LoxList::new(), self);
+            let mut items = self.stack.split_off(self.stack.len() - extra_count);
+
+            self.stack.push(Value::List(rest));
+            self.heap.update_allocation(rest, || rest.items.append(&mut items), self);
+            slot_count += 1;
+        }
+
+        let mut call_frame = CallFrame::new(closure_ptr, self.stack.len() - slot_count - 1);
+        call_frame.arg_count = arg_count;
+
+        if function.is_generator {
+            return self.create_generator(call_frame);
+        }
         self.call_frames.push(call_frame);
         true
     }
 
+    /// Throws an instance of the prelude's `Error` class carrying `message`.
     fn runtime_error(&mut self, message: impl AsRef<str>) {
-        // start moving out functions from borrowing self.
-        runtime_error(&mut self.call_frames, &mut self.had_runtime_error, message);
-        self.call_frames.truncate(1);
+        let error = self.new_error(message.as_ref());
+        self.throw_value(error);
     }
 
-    fn perform_binary_op_plus(&mut self) {
+    fn new_error(&mut self, message: &str) -> Value {
+        let message = self.heap.intern_string_gc(message, self);
+
+        self.stack.push(Value::String(message));
+        let error_class = self.error_class.expect("Runtime error raised while running the prelude.");
+        let mut instance = self.heap.manage_gc(LoxInstance::new(error_class), self);
+        self.stack.pop();
+
+        self.stack.push(Value::Instance(instance));
+        let message_field = self.error_message_field;
+        self.heap.update_allocation(
+            instance,
+            move || {
+                instance.fields.insert(message_field, Value::String(message));
+            },
+            self,
+        );
+
+        self.stack.pop().unwrap()
+    }
+
+    /// Finds the method `name` on the operand below the top `arg_count` values, if that
+    /// operand is an instance.
+    fn operator_method(&self, name: Gc<LoxStr>, arg_count: ArgCount) -> Option<Gc<LoxClosure>> {
+        match self.peek(arg_count as usize) {
+            Value::Instance(instance) => instance.class.methods.get(&name).map(Value::unwrap_closure),
+            _ => None,
+        }
+    }
+
+    fn perform_binary_op_plus(&mut self) -> bool {
         let lhs = self.stack.peek(1);
         let rhs = self.stack.peek(0);
 
@@ -505,62 +1597,85 @@ impl Vm {
                 let string_ref = self.heap.intern_string_gc(acc, self);
                 res = string_ref.into();
             }
-            (Value::Number(lhs), Value::Number(rhs)) => {
-                res = (*lhs + *rhs).into();
+            (lhs, rhs) if as_float(lhs).is_some() && as_float(rhs).is_some() => {
+                return self.perform_arithmetic(&ADD);
             }
             _ => {
                 self.runtime_error("Operands must both be either numbers or strings");
-                return;
+                return false;
             }
         }
 
         self.stack.pop();
         self.stack.pop();
         self.stack.push(res);
+        true
     }
 
-    fn perform_binary_op<T, V>(&mut self, op: impl Fn(T, T) -> V)
-    where
-        Value: From<V>,
-        for<'a> T: TryFrom<&'a Value>,
-        T: Copy,
-    {
-        self.perform_binary_op_gen(op, "Operands must both be either numbers.");
-    }
-
-    fn perform_binary_op_gen<T, V>(&mut self, op: impl Fn(T, T) -> V, error_msg: &str)
-    where
-        Value: From<V>,
-        for<'a> T: TryFrom<&'a Value>,
-        T: Copy,
-    {
-        let lhs = self.stack.peek(1).try_into();
-        let rhs = self.stack.peek(0).try_into();
-
-        let temp = (lhs, rhs);
-        match &temp {
-            (Ok(ref lhs), Ok(ref rhs)) => {
-                let res = op(*lhs, *rhs).into();
-                drop(temp);
-                self.stack.pop();
-                self.stack.pop();
-                self.stack.push(res);
+    /// Applies an arithmetic operator to the top two values. Integers stay exact, with ints
+    /// that overflow redone as BigInts, while a float operand makes the result a float.
+    fn perform_arithmetic(&mut self, op: &ArithmeticOp) -> bool {
+        let (lhs, rhs) = (*self.peek(1), *self.peek(0));
+        let res = match (lhs, rhs, op.integer) {
+            (Value::Int(lhs), Value::Int(rhs), Some((int_op, big_op))) => match int_op(lhs, rhs) {
+                Some(res) => Value::Int(res),
+                None => self.big_int_value(big_op(&BigInt::from_int(lhs), &BigInt::from_int(rhs))),
+            },
+            (Value::BigInt(_), _, Some((_, big_op))) | (_, Value::BigInt(_), Some((_, big_op)))
+                if as_big_int(&lhs).is_some() && as_big_int(&rhs).is_some() =>
+            {
+                let res = big_op(&as_big_int(&lhs).unwrap(), &as_big_int(&rhs).unwrap());
+                self.big_int_value(res)
             }
-            _ => {
-                drop(temp);
-                self.runtime_error(error_msg);
+            (lhs, rhs, _) => match (as_float(&lhs), as_float(&rhs)) {
+                (Some(lhs), Some(rhs)) => Value::Number((op.float)(lhs, rhs)),
+                _ => {
+                    self.runtime_error("Operands must both be either numbers.");
+                    return false;
+                }
+            },
+        };
+
+        self.stack.pop();
+        self.stack.pop();
+        self.stack.push(res);
+        true
+    }
+
+    /// Compares the top two values, exactly for integers and as floats otherwise.
+    /// `test` decides the result from how the left operand orders against the right.
+    fn perform_comparison(&mut self, test: fn(Ordering) -> bool) -> bool {
+        let ordering = match (*self.peek(1), *self.peek(0)) {
+            (Value::Int(lhs), Value::Int(rhs)) => Some(lhs.cmp(&rhs)),
+            // Strings are ordered lexicographically by their characters.
+            (Value::String(lhs), Value::String(rhs)) => Some(lhs.as_str().cmp(rhs.as_str())),
+            (lhs, rhs) if as_big_int(&lhs).is_some() && as_big_int(&rhs).is_some() => {
+                Some(as_big_int(&lhs).unwrap().cmp(&as_big_int(&rhs).unwrap()))
             }
-        }
+            // NaN isn't ordered against anything, so every comparison with it is false.
+            (lhs, rhs) => match (as_float(&lhs), as_float(&rhs)) {
+                (Some(lhs), Some(rhs)) => lhs.partial_cmp(&rhs),
+                _ => {
+                    self.runtime_error("Operands must both be either numbers or strings.");
+                    return false;
+                }
+            },
+        };
+
+        self.stack.pop();
+        self.stack.pop();
+        self.stack.push(Value::Boolean(ordering.map_or(false, test)));
+        true
     }
 
-    fn define_method(&mut self, str_ptr: Gc<LoxStr>) {
+    fn define_method(&mut self, str_ptr: Gc<LoxStr>, table: fn(&mut LoxClass) -> &mut Fields) {
         let method = *self.peek(0);
         let mut class = self.peek(1).unwrap_class();
 
         self.heap.update_allocation(
             class,
             move || {
-                class.methods.insert(str_ptr, method);
+                table(&mut class).insert(str_ptr, method);
             },
             self,
         );
@@ -583,7 +1698,494 @@ impl Vm {
         }
     }
 
+    /// Pushes the module at `path`, first running it in a new call frame if it has not
+    /// been imported before.
+    fn import_module(&mut self, path: Gc<LoxStr>) -> bool {
+        let importer = self.call_frames.last().unwrap().closure.module;
+        let full_path = match fs::canonicalize(importer.directory().join(path.as_str())) {
+            Ok(full_path) => full_path,
+            Err(_) => {
+                self.runtime_error(format!("Could not find module '{}'.", path));
+                return false;
+            }
+        };
+
+        if let Some(module) = self.modules.get(&full_path) {
+            if !module.loaded {
+                self.runtime_error(format!("Cyclic import of module '{}'.", path));
+                return false;
+            }
+
+            self.stack.push(Value::Module(*module));
+            self.call_frames.last_mut().unwrap().ip.next();
+            return true;
+        }
+
+        let source = match fs::read_to_string(&full_path) {
+            Ok(source) => source,
+            Err(_) => {
+                self.runtime_error(format!("Could not read module '{}'.", path));
+                return false;
+            }
+        };
+
+        let function = match Compiler::new_module(&source, &self.heap, path).compile() {
+            Some(function) => function,
+            None => {
+                self.runtime_error(format!("Could not compile module '{}'.", path));
+                return false;
+            }
+        };
+
+        if self.call_frames.len() == FRAMES_MIN_SIZE {
+            self.runtime_error("Stack overflow.");
+            return false;
+        }
+
+        // The module takes the place of the callee in its top level frame, from where
+        // the module's final return hands it back to the importer.
+        self.stack.push(Value::Function(function));
+        let module = self.heap.manage_gc(LoxModule::new(path, full_path.clone()), self);
+        self.stack.pop();
+
+        let frame_index = self.stack.len();
+        self.stack.push(Value::Module(module));
+        self.stack.push(Value::Function(function));
+        let closure_ptr = self.heap.manage_gc(LoxClosure::new(function, module), self);
+        self.stack.pop();
+
+        self.modules.insert(full_path, module);
+        self.call_frames.push(CallFrame::new(closure_ptr, frame_index));
+        self.loading_modules.push((module, self.call_frames.len()));
+        true
+    }
+
+    /// Moves the callee and arguments of `call_frame` onto a new generator's stack and
+    /// returns the generator to the caller without running any of its body.
+    fn create_generator(&mut self, mut call_frame: CallFrame) -> bool {
+        let context = ExecutionContext::new(FRAME_STACK_SIZE);
+        let coroutine = LoxCoroutine::new(CoroutineKind::Generator, call_frame.closure, context);
+        let mut generator = self.heap.manage_gc(coroutine, self);
+
+        let frame_index = call_frame.frame_index;
+        generator.context.stack.extend(self.stack.drain(frame_index..));
+        call_frame.frame_index = 0;
+        generator.context.call_frames.push(call_frame);
+
+        self.stack.push(Value::Generator(generator));
+
+        // Since we skip ip.next after calls we need to add call ip.next for native calls ourselves.
+        self.call_frames.last_mut().unwrap().ip.next();
+        true
+    }
+
+    /// Creates a fiber from the function passed to `Fiber`. Its body starts running on the first resume.
+    fn create_fiber(&mut self, arg_count: ArgCount) -> bool {
+        if arg_count != 1 {
+            self.runtime_error(format!("Expected 1 arguments but got {}.", arg_count));
+            return false;
+        }
+
+        let closure = match *self.peek(0) {
+            Value::Closure(closure) if !closure.function.is_generator => closure,
+            _ => {
+                self.runtime_error("Fiber must be created from a function.");
+                return false;
+            }
+        };
+
+        let mut context = ExecutionContext::new(FRAME_STACK_SIZE);
+        context.stack.push(Value::Closure(closure));
+        let fiber = self.heap.manage_gc(LoxCoroutine::new(CoroutineKind::Fiber, closure, context), self);
+
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(Value::Fiber(fiber));
+
+        // Since we skip ip.next after calls we need to add call ip.next for native calls ourselves.
+        self.call_frames.last_mut().unwrap().ip.next();
+        true
+    }
+
+    fn invoke_fiber(&mut self, mut fiber: Gc<LoxCoroutine>, method_name: Gc<LoxStr>, arg_count: ArgCount) -> bool {
+        let max_arity = match method_name.as_str() {
+            "resume" => 1,
+            "isDone" => 0,
+            _ => {
+                self.runtime_error(format!("Undefined method '{}' on fiber.", method_name));
+                return false;
+            }
+        };
+
+        if arg_count > max_arity {
+            self.runtime_error(format!("Expected at most {} arguments but got {}.", max_arity, arg_count));
+            return false;
+        }
+
+        let sent = if arg_count == 1 { *self.peek(0) } else { Value::Nil };
+        self.stack.truncate(self.stack.len() - arg_count as usize - 1);
+
+        match (method_name.as_str(), fiber.state) {
+            ("isDone", state) => {
+                self.stack.push(Value::Boolean(state == CoroutineState::Done));
+                self.call_frames.last_mut().unwrap().ip.next();
+                true
+            }
+            (_, CoroutineState::Running) => {
+                self.runtime_error("Fiber is already running.");
+                false
+            }
+            (_, CoroutineState::Done) => {
+                self.runtime_error("Can't resume a finished fiber.");
+                false
+            }
+            (_, state) => {
+                fiber.state = CoroutineState::Running;
+                self.running_coroutines.push(fiber);
+                self.switch_context(&mut fiber.context);
+
+                if state == CoroutineState::Suspended {
+                    // The value becomes the result of the Fiber.yield call the fiber is paused at.
+                    self.stack.push(sent);
+                    return true;
+                }
+
+                // The first value resumed with is passed to the fiber's function, if it takes one.
+                let function = &fiber.closure.function;
+                let arg_count = if function.max_arity() > 0 || function.has_rest {
+                    self.stack.push(sent);
+                    1
+                } else {
+                    0
+                };
+                self.call(fiber.closure, arg_count)
+            }
+        }
+    }
+
+    /// Suspends the innermost running fiber, handing `value` to whatever resumed it.
+    fn yield_fiber(&mut self, arg_count: ArgCount) -> bool {
+        if arg_count > 1 {
+            self.runtime_error(format!("Expected at most 1 arguments but got {}.", arg_count));
+            return false;
+        }
+
+        match self.running_coroutines.last() {
+            Some(coroutine) if coroutine.kind == CoroutineKind::Fiber => {}
+            Some(_) => {
+                self.runtime_error("Can't yield a fiber from inside a generator.");
+                return false;
+            }
+            None => {
+                self.runtime_error("Can't yield outside of a fiber.");
+                return false;
+            }
+        }
+
+        let value = if arg_count == 1 { *self.peek(0) } else { Value::Nil };
+        self.stack.truncate(self.stack.len() - arg_count as usize - 1);
+
+        // The fiber picks up after the call to Fiber.yield when it's resumed.
+        self.call_frames.last_mut().unwrap().ip.next();
+        self.leave_coroutine(CoroutineState::Suspended, value);
+        true
+    }
+
+    fn invoke_generator(&mut self, mut generator: Gc<LoxCoroutine>, method_name: Gc<LoxStr>, arg_count: ArgCount) -> bool {
+        let max_arity = match method_name.as_str() {
+            "next" => 1,
+            "isDone" => 0,
+            _ => {
+                self.runtime_error(format!("Undefined method '{}' on generator.", method_name));
+                return false;
+            }
+        };
+
+        if arg_count > max_arity {
+            self.runtime_error(format!("Expected at most {} arguments but got {}.", max_arity, arg_count));
+            return false;
+        }
+
+        let sent = if arg_count == 1 { *self.peek(0) } else { Value::Nil };
+        self.stack.truncate(self.stack.len() - arg_count as usize - 1);
+
+        let result = match (method_name.as_str(), generator.state) {
+            ("isDone", state) => Value::Boolean(state == CoroutineState::Done),
+            (_, CoroutineState::Running) => {
+                self.runtime_error("Generator is already running.");
+                return false;
+            }
+            (_, CoroutineState::Done) => Value::Nil,
+            (_, state) => {
+                generator.state = CoroutineState::Running;
+                self.running_coroutines.push(generator);
+                self.switch_context(&mut generator.context);
+
+                // The value sent in becomes the result of the yield the generator is paused at.
+                if state == CoroutineState::Suspended {
+                    self.stack.push(sent);
+                }
+                return true;
+            }
+        };
+
+        self.stack.push(result);
+        self.call_frames.last_mut().unwrap().ip.next();
+        true
+    }
+
+    /// Switches from the innermost running coroutine back to its resumer, which receives `result`.
+    fn leave_coroutine(&mut self, state: CoroutineState, result: Value) {
+        let mut coroutine = self.running_coroutines.pop().unwrap();
+        self.switch_context(&mut coroutine.context);
+        coroutine.state = state;
+
+        self.stack.push(result);
+
+        // Step the resumer past its call to next() or resume().
+        self.call_frames.last_mut().unwrap().ip.next();
+    }
+
+    fn switch_context(&mut self, context: &mut ExecutionContext) {
+        mem::swap(&mut self.stack, &mut context.stack);
+        mem::swap(&mut self.call_frames, &mut context.call_frames);
+        mem::swap(&mut self.open_upvalues, &mut context.open_upvalues);
+        mem::swap(&mut self.loading_modules, &mut context.loading_modules);
+    }
+
+    /// Grows the stack ahead of a call when it's running short. Coroutine stacks start out
+    /// small, so open upvalues pointing into them are moved along with the values.
+    fn reserve_frame_stack(&mut self) {
+        if self.stack.capacity() - self.stack.len() >= FRAME_STACK_SIZE {
+            return;
+        }
+
+        let old_base = self.stack.as_ptr();
+        self.stack.reserve(self.stack.capacity().max(FRAME_STACK_SIZE));
+        let new_base = self.stack.as_mut_ptr();
+
+        for upvalue in self.open_upvalues.iter_mut() {
+            let index = (upvalue.value_ptr() as usize - old_base as usize) / mem::size_of::<Value>();
+            upvalue.location = NonNull::new(new_base.wrapping_add(index)).unwrap();
+        }
+    }
+
+    /// Makes a value out of `value`, allocating a BigInt only when it's too large for an int.
+    fn big_int_value(&mut self, value: BigInt) -> Value {
+        match value.to_int() {
+            Some(int) => Value::Int(int),
+            None => Value::BigInt(self.heap.manage_gc(value, self)),
+        }
+    }
+
+    /// Checks that `index` is an integer within `list`, throwing if it isn't.
+    fn list_index(&mut self, list: Gc<LoxList>, index: Value) -> Option<usize> {
+        let index = match index {
+            Value::Int(index) => index,
+            Value::Number(index) if index.fract() == 0.0 => index as Int,
+            Value::BigInt(index) => {
+                self.runtime_error(format!("List index {} out of range.", *index));
+                return None;
+            }
+            _ => {
+                self.runtime_error("List index must be an integer.");
+                return None;
+            }
+        };
+
+        if index < 0 || index as usize >= list.items.len() {
+            self.runtime_error(format!("List index {} out of range.", index));
+            return None;
+        }
+
+        Some(index as usize)
+    }
+
+    fn invoke_list(&mut self, mut list: Gc<LoxList>, method_name: Gc<LoxStr>, arg_count: ArgCount) -> bool {
+        let arity = match method_name.as_str() {
+            "len" | "pop" => 0,
+            "push" => 1,
+            _ => {
+                self.runtime_error(format!("Undefined method '{}' on list.", method_name));
+                return false;
+            }
+        };
+
+        if arg_count != arity {
+            self.runtime_error(format!("Expected {} arguments but got {}.", arity, arg_count));
+            return false;
+        }
+
+        let args_start = self.stack.len() - arg_count as usize;
+        let result = match method_name.as_str() {
+            "len" => Value::Int(list.items.len() as Int),
+            "pop" => list.items.pop().unwrap_or(Value::Nil),
+            _ => {
+                let item = self.stack[args_start];
+                self.heap.update_allocation(list, || list.items.push(item), self);
+                Value::Nil
+            }
+        };
+
+        self.stack.truncate(args_start - 1);
+        self.stack.push(result);
+
+        // Since we skip ip.next after calls we need to add call ip.next for native calls ourselves.
+        self.call_frames.last_mut().unwrap().ip.next();
+        true
+    }
+
+    /// Calls one of the built-in string methods. Positions and lengths count characters
+    /// rather than bytes, the same way for-in loops step through strings.
+    fn invoke_string(&mut self, string: Gc<LoxStr>, method_name: Gc<LoxStr>, arg_count: ArgCount) -> bool {
+        let (min_arity, max_arity) = match method_name.as_str() {
+            "len" | "trim" | "upper" | "lower" | "chars" | "toNumber" => (0, 0),
+            "indexOf" | "split" | "startsWith" => (1, 1),
+            "substring" => (1, 2),
+            "replace" => (2, 2),
+            _ => {
+                self.runtime_error(format!("Undefined method '{}' on string.", method_name));
+                return false;
+            }
+        };
+
+        if arg_count < min_arity || arg_count > max_arity {
+            let expected = if min_arity == max_arity {
+                min_arity.to_string()
+            } else {
+                format!("{} to {}", min_arity, max_arity)
+            };
+            self.runtime_error(format!("Expected {} arguments but got {}.", expected, arg_count));
+            return false;
+        }
+
+        let args_start = self.stack.len() - arg_count as usize;
+        let mut string_args = Vec::with_capacity(arg_count as usize);
+        let mut int_args = Vec::with_capacity(arg_count as usize);
+        for arg in self.stack[args_start..].iter() {
+            match arg {
+                Value::String(arg) => string_args.push(*arg),
+                Value::Int(arg) => int_args.push(*arg),
+                _ => {}
+            }
+        }
+
+        let text = string.as_str();
+        let result = match method_name.as_str() {
+            "len" => Value::Int(text.chars().count() as Int),
+            "trim" => Value::String(self.heap.intern_string_gc(text.trim(), self)),
+            "upper" => Value::String(self.heap.intern_string_gc(text.to_uppercase(), self)),
+            "lower" => Value::String(self.heap.intern_string_gc(text.to_lowercase(), self)),
+            "toNumber" => parse_number(text.trim(), &self.heap),
+            "chars" => {
+                let chars = text.chars().map(String::from).collect();
+                self.string_list(chars)
+            }
+            "substring" => {
+                if int_args.len() != arg_count as usize {
+                    self.runtime_error("Substring positions must be integers.");
+                    return false;
+                }
+
+                let len = text.chars().count() as Int;
+                let start = int_args[0];
+                let end = int_args.get(1).copied().unwrap_or(len);
+                if start < 0 || start > end || end > len {
+                    self.runtime_error(format!("Substring range {}..{} out of range.", start, end));
+                    return false;
+                }
+
+                let substring: String = text.chars().skip(start as usize).take((end - start) as usize).collect();
+                Value::String(self.heap.intern_string_gc(substring, self))
+            }
+            _ => {
+                if string_args.len() != arg_count as usize {
+                    self.runtime_error(format!("Arguments to '{}' must be strings.", method_name));
+                    return false;
+                }
+
+                match method_name.as_str() {
+                    "indexOf" => {
+                        let index = text
+                            .find(string_args[0].as_str())
+                            .map_or(-1, |byte_index| text[..byte_index].chars().count() as Int);
+                        Value::Int(index)
+                    }
+                    "startsWith" => Value::Boolean(text.starts_with(string_args[0].as_str())),
+                    "replace" => {
+                        let replaced = text.replace(string_args[0].as_str(), string_args[1].as_str());
+                        Value::String(self.heap.intern_string_gc(replaced, self))
+                    }
+                    _ => {
+                        // Splitting on an empty separator gives the characters.
+                        let separator = string_args[0].as_str();
+                        let parts = if separator.is_empty() {
+                            text.chars().map(String::from).collect()
+                        } else {
+                            text.split(separator).map(String::from).collect()
+                        };
+                        self.string_list(parts)
+                    }
+                }
+            }
+        };
+
+        self.stack.truncate(args_start - 1);
+        self.stack.push(result);
+
+        // Since we skip ip.next after calls we need to add call ip.next for native calls ourselves.
+        self.call_frames.last_mut().unwrap().ip.next();
+        true
+    }
+
+    /// Makes a list of strings. Each string is kept on the stack until the list holds it.
+    fn string_list(&mut self, parts: Vec<String>) -> Value {
+        let count = parts.len();
+        for part in parts {
+            let part = self.heap.intern_string_gc(part, self);
+            self.stack.push(Value::String(part));
+        }
+
+        let mut list = self.heap.manage_gc(LoxList::new(), self);
+        let mut items = self.stack.split_off(self.stack.len() - count);
+
+        self.stack.push(Value::List(list));
+        self.heap.update_allocation(list, || list.items.append(&mut items), self);
+        self.stack.pop().unwrap()
+    }
+
     fn invoke(&mut self, method_name: Gc<LoxStr>, arg_count: ArgCount) -> bool {
+        if let Value::List(list) = *self.peek(arg_count as usize) {
+            return self.invoke_list(list, method_name, arg_count);
+        }
+
+        if let Value::String(string) = *self.peek(arg_count as usize) {
+            return self.invoke_string(string, method_name, arg_count);
+        }
+
+        if let Value::Generator(generator) = *self.peek(arg_count as usize) {
+            return self.invoke_generator(generator, method_name, arg_count);
+        }
+
+        if let Value::Fiber(fiber) = *self.peek(arg_count as usize) {
+            return self.invoke_fiber(fiber, method_name, arg_count);
+        }
+
+        if let Value::Module(module) = *self.peek(arg_count as usize) {
+            if let Some(value) = module.globals.get(&method_name) {
+                let len = self.stack.len();
+                let value = *value;
+
+                self.stack[len - 1 - arg_count as usize] = value;
+                return self.call_value(value, arg_count);
+            }
+
+            self.runtime_error(format!(
+                "Undefined name '{}' in module '{}'.",
+                method_name, module.name
+            ));
+            return false;
+        }
+
         if let Value::Instance(instance) = *self.peek(arg_count as usize) {
             if let Some(field_val) = instance.fields.get(&method_name) {
                 let len = self.stack.len();
@@ -593,7 +2195,35 @@ impl Vm {
                 return self.call_value(field_val, arg_count);
             }
 
+            if let Some(getter) = instance.class.getters.get(&method_name) {
+                // The getter runs on a copy of the receiver above the arguments, and its caller
+                // stays on this instruction until the result has been called.
+                let getter = getter.unwrap_closure();
+                self.stack.push(Value::Instance(instance));
+                self.call_frames.last_mut().unwrap().getter_call = Some(arg_count);
+                return self.call(getter, 0);
+            }
+
             return self.invoke_from_class(instance.class, method_name, arg_count);
+        } else if let Value::Class(class) = *self.peek(arg_count as usize) {
+            if Some(class) == self.fiber_class && method_name.as_str() == "yield" {
+                return self.yield_fiber(arg_count);
+            }
+
+            // The class stays in the receiver slot and becomes 'this'.
+            if let Some(method) = class.static_methods.get(&method_name) {
+                let closure_ptr = method.unwrap_closure();
+                return self.call(closure_ptr, arg_count);
+            }
+
+            if let Some(variant) = class.variant(method_name) {
+                let len = self.stack.len();
+                self.stack[len - 1 - arg_count as usize] = variant;
+                return self.call_value(variant, arg_count);
+            }
+
+            self.runtime_error(format!("Undefined property '{}'.", method_name));
+            return false;
         } else {
             self.runtime_error("Only instances have methods.");
             return false;
@@ -623,6 +2253,40 @@ fn initialize_built_ins(heap: &Heap, globals: &mut Globals) {
 
     globals.insert(clock_native_name, clock_native);
     globals.insert(value_to_str_name, value_to_str);
+
+    let int_converter = Value::NativeFunction(heap.manage(LoxNativeFun::new(IntConverter::new())));
+    let float_converter = Value::NativeFunction(heap.manage(LoxNativeFun::new(FloatConverter::new())));
+
+    globals.insert(heap.intern_string("int"), int_converter);
+    globals.insert(heap.intern_string("float"), float_converter);
+
+    // Reflection on instances and classes.
+    let reflection_natives: [(&str, LoxNativeFun); 8] = [
+        ("fields", LoxNativeFun::new(FieldsNative::new())),
+        ("methods", LoxNativeFun::new(MethodsNative::new())),
+        ("getField", LoxNativeFun::new(GetFieldNative::new())),
+        ("setField", LoxNativeFun::new(SetFieldNative::new())),
+        ("hasField", LoxNativeFun::new(HasFieldNative::new())),
+        ("deleteField", LoxNativeFun::new(DeleteFieldNative::new())),
+        ("respondsTo", LoxNativeFun::new(RespondsToNative::new())),
+        ("classOf", LoxNativeFun::new(ClassOfNative::new())),
+    ];
+
+    for (name, native) in reflection_natives {
+        globals.insert(heap.intern_string(name), Value::NativeFunction(heap.manage(native)));
+    }
+}
+
+/// Parses an int, or a float when the text isn't one, giving nil for anything else.
+fn parse_number(text: &str, heap: &Heap) -> Value {
+    if let Some(int) = BigInt::parse(text, 10) {
+        return int.into_value(heap);
+    }
+
+    match text.parse::<Number>() {
+        Ok(number) if number.is_finite() => Value::Number(number),
+        _ => Value::Nil,
+    }
 }
 
 fn is_falsey(value: &Value) -> bool {
@@ -632,17 +2296,123 @@ fn is_falsey(value: &Value) -> bool {
     }
 }
 
+/// How an arithmetic operator applies to each kind of number.
+struct ArithmeticOp {
+    /// The operation on ints, which returns None on overflow, and on BigInts. This is
+    /// None for operators that always give a float.
+    integer: Option<(fn(Int, Int) -> Option<Int>, fn(&BigInt, &BigInt) -> BigInt)>,
+    float: fn(Number, Number) -> Number,
+}
+
+const ADD: ArithmeticOp = ArithmeticOp {
+    integer: Some((Int::checked_add, BigInt::add)),
+    float: Number::add,
+};
+
+const SUBTRACT: ArithmeticOp = ArithmeticOp {
+    integer: Some((Int::checked_sub, BigInt::sub)),
+    float: Number::sub,
+};
+
+const MULTIPLY: ArithmeticOp = ArithmeticOp {
+    integer: Some((Int::checked_mul, BigInt::mul)),
+    float: Number::mul,
+};
+
+// Division always gives a float, even for two integers.
+const DIVIDE: ArithmeticOp = ArithmeticOp {
+    integer: None,
+    float: Number::div,
+};
+
+/// Reads a number of any kind as a float.
+fn as_float(value: &Value) -> Option<Number> {
+    match value {
+        Value::Number(value) => Some(*value),
+        Value::Int(value) => Some(*value as Number),
+        Value::BigInt(value) => Some(value.to_float()),
+        _ => None,
+    }
+}
+
+/// Reads an integer of either kind as a BigInt.
+fn as_big_int(value: &Value) -> Option<BigInt> {
+    match value {
+        Value::Int(value) => Some(BigInt::from_int(*value)),
+        Value::BigInt(value) => Some((**value).clone()),
+        _ => None,
+    }
+}
+
+/// Compares an int or BigInt with a float without rounding either of them.
+fn integer_equals_float(integer: &Value, float: Number) -> bool {
+    if float.fract() != 0.0 {
+        return false;
+    }
+
+    match integer {
+        // Every whole float in this range converts to an int exactly.
+        Value::Int(int) if float >= Int::MIN as Number && float < -(Int::MIN as Number) => {
+            float as Int == *int
+        }
+        _ => BigInt::from_float(float) == as_big_int(integer),
+    }
+}
+
 fn check_equals(lhs: &Value, rhs: &Value) -> bool {
+    // Integers and floats are equal when the float is exactly the integer's value.
+    match (lhs, rhs) {
+        (integer @ (Value::Int(_) | Value::BigInt(_)), Value::Number(float))
+        | (Value::Number(float), integer @ (Value::Int(_) | Value::BigInt(_))) => {
+            return integer_equals_float(integer, *float)
+        }
+        _ => {}
+    }
+
     if mem::discriminant(lhs) != mem::discriminant(rhs) {
         return false;
     }
 
     match (lhs, rhs) {
-        (Value::Nil, Value::Nil) => true,
-        (Value::Boolean(lhs), Value::Boolean(rhs)) => *lhs == *rhs,
-        (Value::Number(lhs), Value::Number(rhs)) => *lhs == *rhs,
         (Value::String(lhs), Value::String(rhs)) => **lhs == **rhs,
-        _ => panic!("unreachable"),
+        // Binding the same method to the same receiver twice gives equal bound methods.
+        (Value::BoundMethod(lhs), Value::BoundMethod(rhs)) => {
+            lhs.method == rhs.method && is_identical(&lhs.receiver, &rhs.receiver)
+        }
+        // Enum variants carrying values are equal when they're the same variant with equal values.
+        (Value::Instance(lhs), Value::Instance(rhs)) if lhs.class.variant.is_some() && lhs.class == rhs.class => {
+            lhs.fields.len() == rhs.fields.len()
+                && lhs
+                    .fields
+                    .iter()
+                    .all(|(name, value)| rhs.fields.get(name).map_or(false, |other| check_equals(value, other)))
+        }
+        _ => is_identical(lhs, rhs),
+    }
+}
+
+/// Compares values by identity, as the `is` operator does. Heap objects are identical
+/// only when they're the same object.
+fn is_identical(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (Value::Nil, Value::Nil) => true,
+        (Value::Boolean(lhs), Value::Boolean(rhs)) => lhs == rhs,
+        (Value::Number(lhs), Value::Number(rhs)) => lhs == rhs,
+        (Value::Int(lhs), Value::Int(rhs)) => lhs == rhs,
+        // Numbers have no identity beyond their value.
+        (Value::BigInt(lhs), Value::BigInt(rhs)) => **lhs == **rhs,
+        (Value::String(lhs), Value::String(rhs)) => lhs == rhs,
+        (Value::Function(lhs), Value::Function(rhs)) => lhs == rhs,
+        (Value::NativeFunction(lhs), Value::NativeFunction(rhs)) => lhs == rhs,
+        (Value::Closure(lhs), Value::Closure(rhs)) => lhs == rhs,
+        (Value::Class(lhs), Value::Class(rhs)) => lhs == rhs,
+        (Value::Instance(lhs), Value::Instance(rhs)) => lhs == rhs,
+        (Value::BoundMethod(lhs), Value::BoundMethod(rhs)) => lhs == rhs,
+        (Value::Module(lhs), Value::Module(rhs)) => lhs == rhs,
+        (Value::List(lhs), Value::List(rhs)) => lhs == rhs,
+        (Value::Generator(lhs), Value::Generator(rhs)) => lhs == rhs,
+        (Value::Fiber(lhs), Value::Fiber(rhs)) => lhs == rhs,
+        _ => false,
     }
 }
 
@@ -654,9 +2424,35 @@ pub struct CallFrame {
     pub closure: Gc<LoxClosure>,
     ip: Curr,
     frame_index: FrameIndex,
+    handlers: Vec<Handler>,
+    pub completions: Vec<Completion>,
+    /// Number of arguments the caller passed, which decides the defaults to evaluate.
+    arg_count: ArgCount,
+    /// The argument count of a call waiting on a getter that was invoked like a method.
+    getter_call: Option<ArgCount>,
 }
 
 impl CallFrame {
+    pub fn mark_if_needed(&self, grey_stack: &mut GreyStack) {
+        self.closure.mark_if_needed(grey_stack);
+
+        for completion in self.completions.iter() {
+            completion.mark_if_needed(grey_stack);
+        }
+    }
+
+    fn new(closure: Gc<LoxClosure>, frame_index: FrameIndex) -> Self {
+        Self {
+            closure,
+            ip: get_cursor(closure.function.chunk.instr_iter()),
+            frame_index,
+            handlers: Vec::new(),
+            completions: Vec::new(),
+            arg_count: 0,
+            getter_call: None,
+        }
+    }
+
     fn get_chunk(&self) -> &Chunk {
         &self.closure.function.chunk
     }
@@ -666,6 +2462,125 @@ impl CallFrame {
     }
 }
 
+/// Names of the methods classes define to overload operators.
+pub struct OperatorMethods {
+    pub add: Gc<LoxStr>,
+    pub subtract: Gc<LoxStr>,
+    pub multiply: Gc<LoxStr>,
+    pub divide: Gc<LoxStr>,
+    pub less: Gc<LoxStr>,
+    pub greater: Gc<LoxStr>,
+    pub equal: Gc<LoxStr>,
+    pub negate: Gc<LoxStr>,
+    pub index: Gc<LoxStr>,
+    /// Called with the index and the value for `target[index] = value`, which evaluates
+    /// to whatever it returns.
+    pub set_index: Gc<LoxStr>,
+}
+
+impl OperatorMethods {
+    fn new(heap: &Heap) -> Self {
+        Self {
+            add: heap.intern_string("__add__"),
+            subtract: heap.intern_string("__sub__"),
+            multiply: heap.intern_string("__mul__"),
+            divide: heap.intern_string("__div__"),
+            less: heap.intern_string("__lt__"),
+            greater: heap.intern_string("__gt__"),
+            equal: heap.intern_string("__eq__"),
+            negate: heap.intern_string("__neg__"),
+            index: heap.intern_string("__index__"),
+            set_index: heap.intern_string("__setindex__"),
+        }
+    }
+
+    pub fn mark_if_needed(&self, grey_stack: &mut GreyStack) {
+        for name in [
+            self.add,
+            self.subtract,
+            self.multiply,
+            self.divide,
+            self.less,
+            self.greater,
+            self.equal,
+            self.negate,
+            self.index,
+            self.set_index,
+        ]
+        .iter()
+        {
+            name.mark_if_needed(grey_stack);
+        }
+    }
+}
+
+/// A stack of values and call frames along with the upvalues still open on that stack.
+/// The vm runs whichever context is in its own fields, while generators and fibers hold the others.
+pub struct ExecutionContext {
+    pub stack: Stack,
+    pub call_frames: Vec<CallFrame>,
+    pub open_upvalues: Vec<Gc<Upvalue>>,
+    /// The modules being loaded on this stack, since their depths are its call frame depths.
+    pub loading_modules: Vec<(Gc<LoxModule>, usize)>,
+}
+
+impl ExecutionContext {
+    pub fn new(stack_size: usize) -> Self {
+        Self {
+            stack: Vec::with_capacity(stack_size),
+            call_frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            loading_modules: Vec::new(),
+        }
+    }
+
+    pub fn mark_if_needed(&self, grey_stack: &mut GreyStack) {
+        for call_frame in self.call_frames.iter() {
+            call_frame.mark_if_needed(grey_stack);
+        }
+
+        for upvalue in self.open_upvalues.iter() {
+            upvalue.mark_if_needed(grey_stack);
+        }
+
+        for value in self.stack.iter() {
+            value.mark_if_needed(grey_stack);
+        }
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.stack.capacity() * mem::size_of::<Value>()
+            + self.call_frames.capacity() * mem::size_of::<CallFrame>()
+            + self.open_upvalues.capacity() * mem::size_of::<Gc<Upvalue>>()
+    }
+}
+
+/// A try block that is currently executing in a call frame.
+#[derive(Debug, Clone, Copy)]
+struct Handler {
+    catch_index: Option<usize>,
+    finally_index: Option<usize>,
+    stack_len: usize,
+    completions_len: usize,
+}
+
+/// What a finally block should resume with once it has run.
+#[derive(Debug, Clone, Copy)]
+pub enum Completion {
+    Normal,
+    Throw(Value),
+    Return(Value),
+}
+
+impl Completion {
+    pub fn mark_if_needed(&self, grey_stack: &mut GreyStack) {
+        match self {
+            Completion::Throw(value) | Completion::Return(value) => value.mark_if_needed(grey_stack),
+            Completion::Normal => {}
+        }
+    }
+}
+
 trait PeekFromTop {
     type Target;
     fn peek(&self, distance: usize) -> &Self::Target;
@@ -680,13 +2595,10 @@ impl PeekFromTop for Vec<Value> {
     }
 }
 
-fn runtime_error(
-    call_frames: &mut Vec<CallFrame>,
-    had_runtime_error: &mut bool,
-    message: impl AsRef<str>,
-) {
-    let message = message.as_ref();
-    eprintln!("{}", message);
+fn stack_trace(call_frames: &mut Vec<CallFrame>) -> String {
+    let main_module = call_frames.first().map(|call_frame| call_frame.closure.module);
+
+    let mut lines = Vec::with_capacity(call_frames.len());
     for call_frame in call_frames.iter_mut().rev() {
         let instr_index = call_frame.ip.peek().unwrap().0;
         let line_no = call_frame.get_chunk().get_line(instr_index);
@@ -696,10 +2608,15 @@ fn runtime_error(
             &call_frame.closure.function.name
         };
 
-        eprintln!("[line {}] in {}", line_no, fun_name);
+        let module = call_frame.closure.module;
+        if Some(module) == main_module {
+            lines.push(format!("[line {}] in {}", line_no, fun_name));
+        } else {
+            lines.push(format!("[line {}] in {} ({})", line_no, fun_name, module.name));
+        }
     }
 
-    *had_runtime_error = true;
+    lines.join("\n")
 }
 
 fn get_callframe(call_frames: &mut Vec<CallFrame>) -> &'static mut CallFrame {